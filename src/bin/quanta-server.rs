@@ -0,0 +1,68 @@
+//! The dedicated server, which doesn't open a window or need a GPU.
+//! Usage: `quanta-server <world directory> [--listen <address>]`
+//! Commands typed into it run like chat commands from an admin, and `stop` saves the world and quits.
+use quanta::common::*;
+use quanta::config::GameConfig;
+use quanta::server::Server;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let arg = |name: &str| {
+        args.iter().position(|x| x == name).map(|i| {
+            args.get(i + 1)
+                .unwrap_or_else(|| panic!("{} needs an address", name))
+        })
+    };
+
+    let world_dir = match args.get(1).filter(|x| !x.starts_with("--")) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            println!("Usage: quanta-server <world directory> [--listen <address>]");
+            std::process::exit(1);
+        }
+    };
+    if !world_dir.exists() {
+        std::fs::create_dir_all(&world_dir).unwrap();
+    }
+
+    // The server's config lives with the world, so each world can have its own seed
+    let config_file = world_dir.join("server.ron");
+    let config = if config_file.exists() {
+        ron::de::from_reader(File::open(config_file).unwrap()).expect("bad config file")
+    } else {
+        let c = GameConfig::default();
+        let s = ron::ser::to_string(&c).unwrap();
+        let mut f = File::create(config_file).unwrap();
+        writeln!(f, "{}", s).unwrap();
+        c
+    };
+
+    let addr = arg("--listen")
+        .cloned()
+        .unwrap_or_else(|| format!("0.0.0.0:{}", DEFAULT_PORT));
+
+    let mut server = Server::new(Arc::new(config), world_dir);
+    server
+        .listen(&addr)
+        .unwrap_or_else(|e| panic!("Couldn't listen on {}: {}", addr, e));
+    server.console();
+    println!("Type 'help' for a list of commands");
+
+    // Ctrl-C and SIGTERM save the world before quitting
+    let stop = server.stop_handle();
+    ctrlc::set_handler(move || {
+        if stop.stopping() {
+            println!("Quitting without saving");
+            std::process::exit(1);
+        }
+        println!("Saving the world, press Ctrl-C again to quit without saving");
+        stop.stop();
+    })
+    .expect("Couldn't set a Ctrl-C handler");
+
+    server.run();
+}
@@ -0,0 +1,102 @@
+use crate::common::*;
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+
+/// The number of bits of a leaf node used for the material. The block state goes above them.
+/// `main.frag` masks the material out with `0xFF`, so this has to match.
+pub const MAT_BITS: u32 = 8;
+const MAT_MASK: u32 = (1 << MAT_BITS) - 1;
+
+/// Which way a block is facing, for things like logs and stairs
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, num_derive::FromPrimitive)]
+pub enum Facing {
+    Up = 0,
+    Down,
+    East,
+    West,
+    North,
+    South,
+}
+
+/// Extra data stored alongside a block's material, packed into 16 bits:
+/// bits 0-2 are the `Facing`, bits 3-6 are the level (a fluid level or growth stage), and bits 8-15 are a tint index.
+/// The default state is 0, which is what blocks without any state use.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+pub struct BlockState(pub u16);
+
+impl BlockState {
+    pub fn facing(self) -> Facing {
+        Facing::from_u16(self.0 & 7).unwrap_or(Facing::Up)
+    }
+
+    pub fn with_facing(self, facing: Facing) -> Self {
+        BlockState(self.0 & !7 | facing as u16)
+    }
+
+    pub fn level(self) -> u8 {
+        (self.0 >> 3 & 15) as u8
+    }
+
+    /// `level` must be less than 16
+    pub fn with_level(self, level: u8) -> Self {
+        assert!(level < 16, "Block levels only go up to 15, not {}", level);
+        BlockState(self.0 & !(15 << 3) | (level as u16) << 3)
+    }
+
+    pub fn tint(self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub fn with_tint(self, tint: u8) -> Self {
+        BlockState(self.0 & 0xFF | (tint as u16) << 8)
+    }
+}
+
+/// A material and its state, which is everything a leaf node stores
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Block {
+    pub mat: Material,
+    pub state: BlockState,
+}
+
+impl Block {
+    pub fn new(mat: Material, state: BlockState) -> Self {
+        Block { mat, state }
+    }
+
+    /// Packs the block into a leaf node.
+    /// Air never has a state, so empty space is always 0 and merges with other empty space.
+    pub fn leaf(self) -> u32 {
+        if self.mat == Material::Air {
+            0
+        } else {
+            (self.state.0 as u32) << (MAT_BITS + 1) | (self.mat as u32) << 1
+        }
+    }
+
+    /// Unpacks a leaf node, or returns None if it isn't one that `leaf()` could have made
+    pub fn decode(node: u32) -> Option<Self> {
+        let mat = Material::from_u32(node >> 1 & MAT_MASK)?;
+        let state = node >> (MAT_BITS + 1);
+        if node & 1 > 0 || state > u16::MAX as u32 {
+            return None;
+        }
+        let block = Block::new(mat, BlockState(state as u16));
+        if block.leaf() == node {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    /// Unpacks a leaf node, using `Material::Wrong` if it isn't valid
+    pub fn from_leaf(node: u32) -> Self {
+        Block::decode(node).unwrap_or_else(|| Material::Wrong.into())
+    }
+}
+
+impl From<Material> for Block {
+    fn from(mat: Material) -> Self {
+        Block::new(mat, BlockState::default())
+    }
+}
@@ -1,11 +1,39 @@
 use crate::common::*;
 use crate::event::*;
+use crate::input::Action;
 use crate::shaders::PushConstants;
+use std::collections::HashSet;
 
 /// In m/s
 pub const MOVE_SPEED: f32 = 10.0;
 pub const SENSITIVITY: f64 = 2.0;
 
+/// Physics in walking mode runs in steps of this many seconds, no matter the frame rate
+pub const TIMESTEP: f32 = 1.0 / 60.0;
+/// The most physics steps we'll run in one frame, so a long frame doesn't make the next one even longer
+const MAX_STEPS: usize = 10;
+
+/// In m/s
+pub const WALK_SPEED: f32 = 4.5;
+pub const SWIM_SPEED: f32 = 3.0;
+/// The upwards speed at the start of a jump, which is enough to get onto a block
+pub const JUMP_SPEED: f32 = 7.0;
+/// In m/s^2
+pub const GRAVITY: f32 = 20.0;
+/// How quickly horizontal velocity catches up with where the player wants to go, per second.
+/// It's lower in the air and in water, so you don't have as much control there.
+const GROUND_FRICTION: f32 = 12.0;
+const AIR_FRICTION: f32 = 1.5;
+const WATER_FRICTION: f32 = 4.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MoveMode {
+    /// Noclip, with no gravity
+    Fly,
+    /// Gravity and collision with the world
+    Walk,
+}
+
 pub struct Camera {
     fov: f32,
     resolution: (f64, f64),
@@ -16,6 +44,14 @@ pub struct Camera {
     rx: f64,
     ry: f64,
     moving: Vector3<f32>, // vec3(right, up, forward)
+    /// The movement actions that are being held down
+    held: HashSet<Action>,
+    pub mode: MoveMode,
+    /// In walking mode, in m/s
+    vel: Vector3<f32>,
+    on_ground: bool,
+    /// Time that hasn't been simulated yet, less than TIMESTEP
+    accum: f32,
 }
 
 impl Camera {
@@ -35,6 +71,11 @@ impl Camera {
             rx: 0.0,
             ry: 0.0,
             moving: Vector3::zeros(),
+            held: HashSet::new(),
+            mode: MoveMode::Fly,
+            vel: Vector3::zeros(),
+            on_ground: false,
+            accum: 0.0,
         }
     }
 
@@ -42,12 +83,92 @@ impl Camera {
         Vector3::new(self.pos.x, self.pos.y, self.pos.z)
     }
 
-    pub fn update(&mut self, delta: f64) {
-        // self.up is the CAMERA up, but jumping moves up in the WORLD
-        let up = Vector3::y();
-        self.pos += self.dir * self.moving.z * delta as f32 * MOVE_SPEED;
-        self.pos += up * self.moving.y * delta as f32 * MOVE_SPEED;
-        self.pos += self.dir.cross(&up).normalize() * self.moving.x * delta as f32 * MOVE_SPEED;
+    /// The player's collision box
+    pub fn aabb(&self) -> Aabb {
+        player_aabb(self.pos())
+    }
+
+    pub fn update(&mut self, delta: f64, world: &crate::world::World<impl Octree>) {
+        match self.mode {
+            MoveMode::Fly => {
+                // self.up is the CAMERA up, but jumping moves up in the WORLD
+                let up = Vector3::y();
+                self.pos += self.dir * self.moving.z * delta as f32 * MOVE_SPEED;
+                self.pos += up * self.moving.y * delta as f32 * MOVE_SPEED;
+                self.pos +=
+                    self.dir.cross(&up).normalize() * self.moving.x * delta as f32 * MOVE_SPEED;
+            }
+            MoveMode::Walk => {
+                self.accum = (self.accum + delta as f32).min(TIMESTEP * MAX_STEPS as f32);
+                while self.accum >= TIMESTEP {
+                    self.step(world);
+                    self.accum -= TIMESTEP;
+                }
+            }
+        }
+    }
+
+    /// Runs one physics step of walking mode
+    #[allow(clippy::float_cmp)]
+    fn step(&mut self, world: &crate::world::World<impl Octree>) {
+        // Wait for the chunk we're in to load, so we don't fall through it
+        if !world.contains_chunk(world_to_chunk(self.pos())) {
+            return;
+        }
+
+        let aabb = self.aabb();
+        let center = (aabb.min + aabb.max) * 0.5;
+        let in_water = world.block(center) == Some(Material::Water);
+
+        // Where the player wants to go, which only depends on which way the camera is facing horizontally
+        let forward = Vector3::new(self.dir.x, 0.0, self.dir.z).normalize();
+        let right = forward.cross(&Vector3::y());
+        let mut wish = forward * self.moving.z + right * self.moving.x;
+        if wish.norm() > 1.0 {
+            wish = wish.normalize();
+        }
+
+        let (speed, friction) = if in_water {
+            (SWIM_SPEED, WATER_FRICTION)
+        } else if self.on_ground {
+            (WALK_SPEED, GROUND_FRICTION)
+        } else {
+            (WALK_SPEED, AIR_FRICTION)
+        };
+        let wish = wish * speed;
+        let f = (friction * TIMESTEP).min(1.0);
+        self.vel.x += (wish.x - self.vel.x) * f;
+        self.vel.z += (wish.z - self.vel.z) * f;
+
+        if in_water {
+            // Water slows you down and mostly cancels out gravity, and you can swim up or down
+            self.vel.y -= GRAVITY * 0.2 * TIMESTEP;
+            if self.moving.y != 0.0 {
+                self.vel.y += (self.moving.y * SWIM_SPEED - self.vel.y) * f;
+            }
+            self.vel.y *= 1.0 - f;
+        } else {
+            self.vel.y -= GRAVITY * TIMESTEP;
+            if self.on_ground && self.moving.y > 0.0 {
+                self.vel.y = JUMP_SPEED;
+            }
+        }
+
+        let (motion, normals) = world.sweep_aabb(aabb, self.vel * TIMESTEP);
+        self.pos += motion;
+
+        // Stop moving into anything we hit
+        self.on_ground = false;
+        for n in normals {
+            if n.y > 0.0 {
+                self.on_ground = true;
+            }
+            for i in 0..3 {
+                if n[i] != 0.0 {
+                    self.vel[i] = 0.0;
+                }
+            }
+        }
     }
 
     pub fn push(&self, origin: [f32; 3], root_size: f32, sun_dir: [f32; 3]) -> PushConstants {
@@ -69,27 +190,30 @@ impl Camera {
 
     pub fn process(&mut self, event: &Event) {
         match event {
-            // /*w*/ my layout
-            Event::KeyPressed(/*0x11*/ 52) => {
-                self.moving.z = 1.0;
-            }
-            // s
-            Event::KeyPressed(/*0x1f*/ 18) => {
-                self.moving.z = -1.0;
-            }
-            Event::KeyReleased(/*0x11*/ 52) | Event::KeyReleased(/*0x1f*/ 18) => {
-                self.moving.z = 0.0;
-            }
-            // a
-            Event::KeyPressed(/*0x1e*/ 24) => {
-                self.moving.x = -1.0;
-            }
-            // d
-            Event::KeyPressed(/*0x20*/ 22) => {
-                self.moving.x = 1.0;
-            }
-            Event::KeyReleased(/*0x1e*/ 24) | Event::KeyReleased(/*0x20*/ 22) => {
-                self.moving.x = 0.0;
+            Event::Action(action, pressed) => {
+                if *pressed {
+                    self.held.insert(*action);
+                } else {
+                    self.held.remove(action);
+                }
+                let held = |a| if self.held.contains(&a) { 1.0 } else { 0.0 };
+                let axis = |pos, neg| held(pos) - held(neg);
+                self.moving = Vector3::new(
+                    axis(Action::Right, Action::Left),
+                    axis(Action::Up, Action::Down),
+                    axis(Action::Forward, Action::Back),
+                );
+
+                if *action == Action::ToggleFly && *pressed {
+                    self.mode = match self.mode {
+                        MoveMode::Fly => MoveMode::Walk,
+                        MoveMode::Walk => MoveMode::Fly,
+                    };
+                    self.vel = Vector3::zeros();
+                    self.on_ground = false;
+                    self.accum = 0.0;
+                    println!("Switched to {:?} mode", self.mode);
+                }
             }
             Event::Mouse(x, y) => {
                 self.rx -= SENSITIVITY * x / self.resolution.0;
@@ -117,6 +241,11 @@ impl Camera {
             Event::Resize(x, y) => {
                 self.resolution = (*x, *y);
             }
+            Event::Teleport(pos) => {
+                self.pos = Point3::from(*pos);
+                self.vel = Vector3::zeros();
+                self.on_ground = false;
+            }
             _ => {}
         }
     }
@@ -4,22 +4,30 @@ use crate::terrain::*;
 use crate::world::*;
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::*;
 use std::sync::Arc;
 
-const CACHE_SIZE: usize = 16;
+/// How many regions the chunk thread keeps in memory
+pub const CACHE_SIZE: usize = 16;
 
+/// The regions we've used most recently, which are written to disk when they leave the cache, when it's flushed, or when it's dropped.
+/// Since it's dropped while unwinding, a panic in the chunk thread doesn't lose anything either.
 struct RegionCache {
     indices: VecDeque<(Vector3<i32>, usize)>,
     regions: Vec<Vec<Option<Vec<u8>>>>,
+    /// Whether each region has changed since it was last written
+    dirty: Vec<bool>,
     path: std::path::PathBuf,
+    /// How many regions are in the cache, so the server can see it
+    occupancy: Arc<AtomicUsize>,
 }
 
 impl RegionCache {
-    fn new() -> Self {
-        let mut chunks_path =
-            app_dirs2::app_root(app_dirs2::AppDataType::UserData, &crate::APP_INFO).unwrap();
-        chunks_path.push("regions");
+    /// Stores regions in the `regions` folder in `world_dir`
+    fn new(world_dir: &std::path::Path, occupancy: Arc<AtomicUsize>) -> Self {
+        let chunks_path = world_dir.join("regions");
         if !chunks_path.exists() {
             std::fs::create_dir_all(&chunks_path).unwrap();
         }
@@ -27,7 +35,44 @@ impl RegionCache {
         RegionCache {
             indices: VecDeque::new(),
             regions: Vec::new(),
+            dirty: Vec::new(),
             path: chunks_path,
+            occupancy,
+        }
+    }
+
+    /// Where region `v` is saved. Loading used to look for `.region.zstd` while saving wrote `.region.zst`,
+    /// so saved regions were never read back - now they are, and bad ones get the corrupt region warning in `_load`.
+    fn region_path(&self, v: Vector3<i32>) -> PathBuf {
+        self.path
+            .join(format!("{},{},{}.region.zst", v.x, v.y, v.z))
+    }
+
+    /// Writes a region to disk. This doesn't panic, so it's safe to call while unwinding
+    fn write(&self, v: Vector3<i32>, region: &[Option<Vec<u8>>]) {
+        use std::fs::File;
+        use std::io::Write;
+
+        let result = File::create(self.region_path(v)).and_then(|f| {
+            let mut f = zstd::stream::write::Encoder::new(f, 3)?;
+            let buf = bincode::serialize(region)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            f.write_all(&buf)?;
+            f.finish()?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            println!("WARNING: couldn't save region {:?}: {}", v, e);
+        }
+    }
+
+    /// Writes every region in the cache that's changed to disk, but keeps them cached
+    fn flush(&mut self) {
+        for &(v, i) in &self.indices {
+            if self.dirty[i] {
+                self.write(v, &self.regions[i]);
+                self.dirty[i] = false;
+            }
         }
     }
 
@@ -35,26 +80,20 @@ impl RegionCache {
         if self.indices.len() < CACHE_SIZE {
             assert_eq!(self.regions.len(), self.indices.len());
             self.regions.push(region);
+            self.dirty.push(false);
             let i = self.regions.len() - 1;
             self.indices.push_front((v, i));
+            self.occupancy.store(self.indices.len(), Ordering::Relaxed);
             i
         } else {
             let (nv, i) = self.indices.pop_back().unwrap();
             self.indices.push_front((v, i));
 
             std::mem::swap(&mut region, &mut self.regions[i]);
-
-            use std::fs::File;
-            use std::io::Write;
-
-            let mut path = self.path.clone();
-            path.push(format!("{},{},{}.region.zst", nv.x, nv.y, nv.z));
-            let f = File::create(path).unwrap();
-            let mut f = zstd::stream::write::Encoder::new(f, 3).unwrap();
-
-            f.write_all(&bincode::serialize(&region).unwrap()).unwrap();
-
-            f.finish().unwrap();
+            if self.dirty[i] {
+                self.write(nv, &region);
+                self.dirty[i] = false;
+            }
 
             i
         }
@@ -71,20 +110,32 @@ impl RegionCache {
         }
 
         // It's not in the cache, so load it from disk
-        let mut path = self.path.clone();
-        path.push(format!("{},{},{}.region.zstd", v.x, v.y, v.z));
+        let path = self.region_path(v);
 
         let region: Vec<Option<Vec<u8>>> = if path.exists() {
             use std::fs::File;
             use std::io::Read;
 
-            let f = File::open(path).unwrap();
-            let mut f = zstd::stream::read::Decoder::new(f).unwrap();
-
-            let mut buf = Vec::new();
-            f.read_to_end(&mut buf).unwrap();
-
-            bincode::deserialize(&buf).unwrap()
+            // Now that saved regions are actually read back, a half-written one shouldn't crash us either
+            let read = || -> std::io::Result<Vec<u8>> {
+                let mut f = zstd::stream::read::Decoder::new(File::open(&path)?)?;
+                let mut buf = Vec::new();
+                f.read_to_end(&mut buf)?;
+                Ok(buf)
+            };
+
+            read()
+                .map_err(|e| e.to_string())
+                .and_then(|buf| bincode::deserialize(&buf).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    println!(
+                        "WARNING: region file for {:?} is corrupt, ignoring it: {}",
+                        v, e
+                    );
+                    (0..REGION_SIZE * REGION_SIZE * REGION_SIZE)
+                        .map(|_| None)
+                        .collect()
+                })
         } else {
             (0..REGION_SIZE * REGION_SIZE * REGION_SIZE)
                 .map(|_| None)
@@ -99,10 +150,16 @@ impl RegionCache {
         let idx = in_region(chunk);
 
         let ri = self._load(v);
-        if let Some(x) = &self.regions[ri][idx] {
-            bincode::deserialize(x).ok()
-        } else {
-            None
+        let c: Chunk = bincode::deserialize(self.regions[ri][idx].as_ref()?).ok()?;
+        match c.validate() {
+            Ok(()) => Some(c),
+            Err(e) => {
+                println!(
+                    "WARNING: saved chunk {:?} is corrupt, regenerating it: {}",
+                    chunk, e
+                );
+                None
+            }
         }
     }
 
@@ -114,6 +171,13 @@ impl RegionCache {
 
         let ri = self._load(v);
         self.regions[ri][idx] = Some(ser);
+        self.dirty[ri] = true;
+    }
+}
+
+impl Drop for RegionCache {
+    fn drop(&mut self) {
+        self.flush();
     }
 }
 
@@ -122,37 +186,38 @@ pub struct ChunkThread {
     ch: (Sender<ChunkMessage>, Receiver<ChunkMessage>),
     config: Arc<GameConfig>,
     world: ArcWorld,
+    /// Where the world is saved
+    world_dir: PathBuf,
+    /// How many regions are cached, for the server's status
+    cached_regions: Arc<AtomicUsize>,
 }
 
 impl ChunkThread {
     pub fn new(
         config: Arc<GameConfig>,
         world: ArcWorld,
+        world_dir: PathBuf,
+        cached_regions: Arc<AtomicUsize>,
         to: Sender<ChunkMessage>,
         from: Receiver<ChunkMessage>,
     ) -> Self {
         ChunkThread {
-            gen: Gen::new(),
+            gen: Gen::new(config.seed),
             ch: (to, from),
             config,
             world,
+            world_dir,
+            cached_regions,
         }
     }
 
     pub fn run(self) {
         let save = self.config.save_chunks;
 
-        let mut cache = RegionCache::new();
+        let mut cache = RegionCache::new(&self.world_dir, Arc::clone(&self.cached_regions));
 
         let mut to_decorate = HashSet::new();
 
-        let mut chunks_path =
-            app_dirs2::app_root(app_dirs2::AppDataType::UserData, &crate::APP_INFO).unwrap();
-        chunks_path.push("chunks");
-        if !chunks_path.exists() {
-            std::fs::create_dir_all(&chunks_path).unwrap();
-        }
-
         let mut to_load = Vec::new();
 
         loop {
@@ -217,13 +282,24 @@ impl ChunkThread {
                         Ok(ChunkMessage::LoadChunks(mut chunks)) => {
                             to_load.append(&mut chunks);
                         }
-                        Ok(ChunkMessage::UnloadChunk(_, _)) => {
-                            // TODO save chunk
+                        Ok(ChunkMessage::UnloadChunk(p, chunk))
+                        | Ok(ChunkMessage::SaveChunk(p, chunk)) => {
+                            if save {
+                                cache.store(p, chunk);
+                            }
                         }
                         Ok(ChunkMessage::Players(players)) => {
                             sort = players;
                         }
+                        Ok(ChunkMessage::Flush) => {
+                            if save {
+                                cache.flush();
+                            }
+                        }
                         Ok(ChunkMessage::Done) => {
+                            if save {
+                                cache.flush();
+                            }
                             self.ch.0.send(ChunkMessage::Done).unwrap();
                             connected = false;
                             break;
@@ -268,15 +344,68 @@ impl ChunkThread {
                     Ok(ChunkMessage::LoadChunks(mut chunks)) => {
                         to_load.append(&mut chunks);
                     }
-                    Ok(ChunkMessage::UnloadChunk(p, chunk)) => {
+                    Ok(ChunkMessage::UnloadChunk(p, chunk))
+                    | Ok(ChunkMessage::SaveChunk(p, chunk)) => {
                         if save {
                             cache.store(p, chunk);
                         }
                     }
                     Ok(ChunkMessage::Players(_)) => {}
+                    Ok(ChunkMessage::Flush) => {
+                        if save {
+                            cache.flush();
+                        }
+                    }
+                    Ok(ChunkMessage::Done) => {
+                        if save {
+                            cache.flush();
+                        }
+                        self.ch.0.send(ChunkMessage::Done).unwrap();
+                        break;
+                    }
                     _ => break,
                 }
             }
         }
+        // If the server went away without sending `Done`, it probably panicked, so save everything we have
+        cache.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_round_trip() {
+        let dir = std::env::temp_dir().join(format!("quanta-regions-{}", std::process::id()));
+        // These used to share region slots, so each one overwrote the other
+        let chunks: Vec<_> = [-5, -4, -3, -1, 0, 1]
+            .iter()
+            .map(|&x| {
+                let mut chunk = Chunk::empty();
+                // A different block in each, so we can tell them apart
+                chunk.set_block(
+                    Vector3::new(x as f32, 0.0, 0.0),
+                    BLOCK_LEVEL,
+                    Material::Stone,
+                );
+                (Vector3::new(x, 0, 0), chunk)
+            })
+            .collect();
+
+        let mut cache = RegionCache::new(&dir, Arc::new(AtomicUsize::new(0)));
+        for (pos, chunk) in &chunks {
+            cache.store(*pos, chunk.clone());
+        }
+        cache.flush();
+        drop(cache);
+
+        // A new cache has to read them from disk
+        let mut cache = RegionCache::new(&dir, Arc::new(AtomicUsize::new(0)));
+        for (pos, chunk) in &chunks {
+            assert_eq!(cache.load(*pos).map(|x| x.0), Some(chunk.0.clone()));
+        }
+        std::fs::remove_dir_all(&dir).ok();
     }
 }
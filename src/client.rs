@@ -3,14 +3,18 @@ use crate::client_world::*;
 use crate::common::*;
 use crate::config::*;
 use crate::event::*;
+use crate::input::Action;
+use crate::shaders::Entity;
 use crate::window::*;
+use enum_iterator::IntoEnumIterator;
 use vulkano::command_buffer::DynamicState;
 
 use std::sync::Arc;
-use vulkano::buffer::{BufferUsage, CpuBufferPool, ImmutableBuffer};
+use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer, CpuBufferPool, ImmutableBuffer};
 use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBuffer};
 use vulkano::descriptor::descriptor_set::{DescriptorSet, PersistentDescriptorSet};
 use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::format::Format;
 use vulkano::framebuffer::Subpass;
 use vulkano::image::{AttachmentImage, ImageUsage};
 use vulkano::pipeline::{
@@ -23,6 +27,46 @@ use specs::World;
 
 const BEAM_RES_FAC: u32 = 8;
 
+/// Other players are drawn in one of these colours, picked by their id
+const PLAYER_COLORS: [[f32; 3]; 6] = [
+    [0.8, 0.2, 0.2],
+    [0.2, 0.4, 0.9],
+    [0.9, 0.8, 0.2],
+    [0.6, 0.2, 0.8],
+    [0.9, 0.5, 0.1],
+    [0.2, 0.8, 0.7],
+];
+
+/// The boxes to draw for the other players: a body, and a small box in front of their face so you can see where they're looking.
+/// There's always at least one entity, since the buffer can't be empty - the first one is inside out so it's never hit.
+fn player_entities(players: &[PlayerState]) -> Vec<Entity> {
+    let entity = |aabb: Aabb, color: [f32; 3]| Entity {
+        lo: [aabb.min.x, aabb.min.y, aabb.min.z, 0.0],
+        hi: [aabb.max.x, aabb.max.y, aabb.max.z, 0.0],
+        color: [color[0], color[1], color[2], 1.0],
+    };
+    let mut entities = vec![Entity {
+        lo: [1.0; 4],
+        hi: [0.0; 4],
+        color: [0.0; 4],
+    }];
+    for p in players {
+        let color = PLAYER_COLORS[p.id % PLAYER_COLORS.len()];
+        entities.push(entity(player_aabb(p.pos), color));
+
+        let look = Vector3::new(p.dir.x, 0.0, p.dir.z);
+        if look.norm() > 0.001 {
+            let nose = p.pos + look.normalize() * PLAYER_SIZE[0] * 0.6;
+            let size = Vector3::repeat(0.1);
+            entities.push(entity(
+                Aabb::new(nose - size, nose + size),
+                [color[0] * 0.5, color[1] * 0.5, color[2] * 0.5],
+            ));
+        }
+    }
+    entities
+}
+
 type BufferlessPipeline = GraphicsPipeline<
     BufferlessDefinition,
     Box<dyn PipelineLayoutAbstract + Send + Sync>,
@@ -30,19 +74,24 @@ type BufferlessPipeline = GraphicsPipeline<
 >;
 
 pub struct Client {
-    tree_buffer: Arc<vulkano::buffer::DeviceLocalBuffer<[u32]>>,
     pipeline: Arc<BufferlessPipeline>,
     desc: Arc<dyn DescriptorSet + Send + Sync>,
+    /// Holds the entities we draw each frame
+    entity_pool: CpuBufferPool<Entity>,
     beam_pipeline: Arc<BufferlessPipeline>,
     beam_framebuffer: Arc<dyn vulkano::framebuffer::FramebufferAbstract + Send + Sync>,
     beam_state: DynamicState,
     beam_desc: Arc<dyn DescriptorSet + Send + Sync>,
     future: Box<dyn GpuFuture + Send + Sync>,
-    pool: CpuBufferPool<u32>,
     recreate_swapchain: bool,
+    /// The materials the player can place, which is everything but Air and Wrong
+    palette: Vec<Material>,
+    /// The index of the material in `palette` we place
+    selected: usize,
+    /// Whether to save the next frame to a file
+    screenshot: bool,
     origin: Vector3<f32>,
     root_size: f32,
-    chunk_slots: HashMap<Vector3<i32>, (usize, usize)>,
     reader_id: ReaderId<Event>,
     tot: f64,
 }
@@ -53,8 +102,11 @@ pub struct ClientData<'a> {
     i: Read<'a, FrameNum>,
     win: WriteExpect<'a, Window>,
     cam: WriteExpect<'a, Camera>,
-    world: WriteExpect<'a, crate::world::World>,
+    world: WriteExpect<'a, crate::world::World<SharedChunk>>,
     channel: Write<'a, EventChannel<Event>>,
+    stats: Read<'a, crate::dag::MemoryStats>,
+    remote: Read<'a, RemotePlayers>,
+    day_time: Read<'a, DayTime>,
 }
 
 impl<'a> System<'a> for Client {
@@ -68,13 +120,15 @@ impl<'a> System<'a> for Client {
             mut cam,
             mut world,
             mut channel,
+            stats,
+            remote,
+            day_time,
         } = data;
 
         let size = win.size();
 
         let delta = time.delta.as_secs_f64();
         self.tot += delta;
-        let time = time.total.as_secs_f64();
 
         // Average FPS over last 30 frames
         if i.0 % 30 == 0 {
@@ -85,6 +139,7 @@ impl<'a> System<'a> for Client {
             );
             self.tot = 0.0;
             println!("Camera at {:?}", cam.pos);
+            println!("{}", *stats);
         }
 
         self.future.cleanup_finished();
@@ -106,11 +161,12 @@ impl<'a> System<'a> for Client {
             Err(err) => panic!("{:?}", err),
         };
 
-        // days / second
-        let sun_speed = 1.0 / (24.0 * 60.0); // a day is 24 minutes
+        // The server decides what time it is
+        let now = std::time::Instant::now();
+        let day = day_time.day(now);
         let sun_dir = Vector3::new(
-            (time * sun_speed * std::f64::consts::PI * 2.0).sin() as f32,
-            (time * sun_speed * std::f64::consts::PI * 2.0).cos() as f32,
+            (day * std::f64::consts::PI * 2.0).sin() as f32,
+            (day * std::f64::consts::PI * 2.0).cos() as f32,
             0.1,
         )
         .normalize();
@@ -132,6 +188,21 @@ impl<'a> System<'a> for Client {
             _dummy2: pc._dummy2,
         };
 
+        let entities = player_entities(&remote.states(now));
+        let entity_desc = Arc::new(
+            PersistentDescriptorSet::start(
+                self.pipeline
+                    .layout()
+                    .descriptor_set_layout(1)
+                    .unwrap()
+                    .clone(),
+            )
+            .add_buffer(self.entity_pool.chunk(entities).unwrap())
+            .unwrap()
+            .build()
+            .unwrap(),
+        );
+
         let command_buffer =
             AutoCommandBufferBuilder::primary_one_time_submit(win.device(), win.queue.family())
                 .unwrap()
@@ -159,15 +230,33 @@ impl<'a> System<'a> for Client {
                         vertices: 4,
                         instances: 1,
                     },
-                    self.desc.clone(),
+                    (self.desc.clone(), entity_desc),
                     pc,
                 )
                 .unwrap()
                 .end_render_pass()
-                .unwrap()
-                .build()
                 .unwrap();
 
+        // Copy the frame we just drew somewhere we can read it
+        let (command_buffer, screenshot) = if self.screenshot {
+            self.screenshot = false;
+            let dims = win.image(frame.image_num).dimensions();
+            let buf = CpuAccessibleBuffer::from_iter(
+                win.device(),
+                BufferUsage::all(),
+                false,
+                (0..dims[0] * dims[1] * 4).map(|_| 0u8),
+            )
+            .unwrap();
+            let command_buffer = command_buffer
+                .copy_image_to_buffer(win.image(frame.image_num), buf.clone())
+                .unwrap();
+            (command_buffer, Some((buf, dims)))
+        } else {
+            (command_buffer, None)
+        };
+        let command_buffer = command_buffer.build().unwrap();
+
         let mut f: Box<dyn GpuFuture + Send + Sync> = Box::new(vulkano::sync::now(win.device()));
         std::mem::swap(&mut f, &mut self.future);
         let f = f
@@ -179,6 +268,17 @@ impl<'a> System<'a> for Client {
 
         match f {
             Ok(f) => {
+                if let Some((buf, size)) = screenshot {
+                    f.wait(None).unwrap();
+                    let bgr = matches!(
+                        win.swapchain.format(),
+                        Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb
+                    );
+                    match save_screenshot(&buf.read().unwrap(), size, bgr) {
+                        Ok(path) => println!("Saved screenshot to {}", path.display()),
+                        Err(e) => println!("WARNING: couldn't save screenshot: {}", e),
+                    }
+                }
                 self.future = Box::new(f) as Box<_>;
             }
             Err(vulkano::sync::FlushError::OutOfDate) => {
@@ -192,16 +292,18 @@ impl<'a> System<'a> for Client {
             }
         }
 
-        channel.single_write(Event::PlayerMove(cam.pos()));
+        channel.single_write(Event::PlayerMove(cam.pos(), cam.dir));
 
-        cam.update(delta);
+        cam.update(delta, &world);
 
+        let mut edits: Vec<(Vector3<i32>, Block)> = Vec::new();
+        let mut select = None;
         for ev in channel.read(&mut self.reader_id) {
             cam.process(&ev);
 
             match ev {
                 Event::Submit(once) => {
-                    let (cmd, origin, root_size, chunk_slots) =
+                    let (cmd, origin, root_size) =
                         once.get().expect("Somebody took the stuff out of Submit!");
 
                     // This shouldn't be necessary
@@ -214,57 +316,87 @@ impl<'a> System<'a> for Client {
 
                     self.origin = origin;
                     self.root_size = root_size;
-                    self.chunk_slots = chunk_slots;
                 }
                 Event::Resize(_, _) => self.recreate_swapchain = true,
                 Event::Quit => (),
-                // Left-click
-                Event::Button(1) => {
-                    println!("You clicked!");
-                    let cast = world.raycast(
-                        cam.pos(),
-                        cam.dir.map(|x| if x.abs() < 0.0001 { 0.0001 } else { x }),
-                        12.0,
-                    );
-                    println!("Found {:?}", cast);
-                    if let Some(RayCast { t, .. }) = cast {
-                        let pos = cam.pos() + cam.dir * (t[0] + 0.05);
-                        world.set_block(pos, Material::Air);
-                        let loc = world_to_chunk(pos);
-                        let chunk = self
-                            .pool
-                            .chunk(world.chunk(loc).unwrap().0.clone())
-                            .unwrap();
-
-                        let slot = self.chunk_slots.get(&loc).unwrap();
-                        let view = vulkano::buffer::BufferSlice::from_typed_buffer_access(
-                            self.tree_buffer.clone(),
-                        )
-                        .slice(slot.0..slot.1)
-                        .unwrap();
-                        let cmd =
-                            AutoCommandBufferBuilder::primary(win.device(), win.queue.family())
-                                .unwrap()
-                                .copy_buffer(chunk, view)
-                                .unwrap()
-                                .build()
-                                .unwrap();
-
-                        // This shouldn't be necessary
-                        let mut f: Box<dyn GpuFuture + Send + Sync> =
-                            Box::new(vulkano::sync::now(win.device()));
-                        std::mem::swap(&mut f, &mut self.future);
-                        f.then_signal_fence_and_flush().unwrap().wait(None).unwrap();
-
-                        self.future = Box::new(cmd.execute(win.queue.clone()).unwrap());
+                Event::Action(Action::Screenshot, true) => self.screenshot = true,
+                Event::Action(Action::Break, true) => {
+                    if let Some(cast) = look_at(&cam, &world) {
+                        edits.push((cast.voxel, Material::Air.into()));
+                    }
+                }
+                Event::Action(Action::Place, true) => {
+                    if let Some(cast) = look_at(&cam, &world) {
+                        let min = cast.adjacent.map(|x| x as f32);
+                        let block = Aabb::new(min, min + Vector3::repeat(1.0));
+                        // Don't place blocks inside the player, or when we're inside the block we hit
+                        if cast.normal != Vector3::zeros() && !cam.aabb().intersects(&block) {
+                            edits.push((cast.adjacent, self.palette[self.selected].into()));
+                        }
+                    }
+                }
+                Event::Action(Action::NextMaterial, true) => select = Some(self.selected + 1),
+                Event::Action(Action::PrevMaterial, true) => {
+                    select = Some(self.selected + self.palette.len() - 1)
+                }
+                Event::Action(Action::SelectMaterial(i), true) => {
+                    if *i < self.palette.len() {
+                        select = Some(*i);
+                    }
+                }
+                Event::Scroll(lines) => {
+                    if *lines > 0.0 {
+                        select = Some(self.selected + 1);
+                    } else if *lines < 0.0 {
+                        select = Some(self.selected + self.palette.len() - 1);
                     }
                 }
                 _ => {}
             }
         }
+        if let Some(i) = select {
+            self.select(i);
+        }
+        for (pos, block) in edits {
+            channel.single_write(Event::SetBlock(pos, block));
+        }
     }
 }
 
+/// Finds the block the player is looking at, if it's close enough to reach
+fn look_at(cam: &Camera, world: &crate::world::World<SharedChunk>) -> Option<RayCast> {
+    world.raycast_filter(
+        cam.pos(),
+        cam.dir.map(|x| if x.abs() < 0.0001 { 0.0001 } else { x }),
+        REACH,
+        // We can't break or place blocks on water
+        |m| m != Material::Water,
+    )
+}
+
+/// Saves a frame as a binary PPM file in the working directory, returning where it went.
+/// `data` has four bytes per pixel, in RGBA or BGRA order.
+fn save_screenshot(data: &[u8], size: [u32; 2], bgr: bool) -> std::io::Result<std::path::PathBuf> {
+    use std::io::Write;
+
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = std::path::PathBuf::from(format!("screenshot-{}.ppm", time.as_millis()));
+
+    let mut f = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    write!(f, "P6\n{} {}\n255\n", size[0], size[1])?;
+    for px in data.chunks(4) {
+        if bgr {
+            f.write_all(&[px[2], px[1], px[0]])?;
+        } else {
+            f.write_all(&px[0..3])?;
+        }
+    }
+    f.flush()?;
+    Ok(path)
+}
+
 impl Client {
     pub fn new(
         window: &Window,
@@ -277,7 +409,7 @@ impl Client {
             window.device(),
             window.queue.clone(),
             conn,
-            Vector3::zeros(),
+            cam.pos(),
             config,
             events.register_reader(),
         );
@@ -427,27 +559,42 @@ impl Client {
             .unwrap(),
         );
 
-        let pool = vulkano::buffer::CpuBufferPool::upload(window.device());
+        let entity_pool = CpuBufferPool::new(
+            window.device(),
+            BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::none()
+            },
+        );
 
         (
             Client {
-                tree_buffer,
                 pipeline,
                 desc,
+                entity_pool,
                 beam_pipeline,
                 beam_framebuffer,
                 beam_state,
                 beam_desc,
                 future,
-                pool,
-                chunk_slots: HashMap::new(),
                 reader_id: events.register_reader(),
                 origin: cam.pos().map(|x| x % CHUNK_SIZE),
                 root_size: 0.0,
                 recreate_swapchain: false,
+                palette: Material::into_enum_iter()
+                    .filter(|&m| m != Material::Air && m != Material::Wrong)
+                    .collect(),
+                selected: 0,
+                screenshot: false,
                 tot: 0.0,
             },
             c,
         )
     }
+
+    /// Selects the material at this index in the palette, wrapping around
+    fn select(&mut self, i: usize) {
+        self.selected = i % self.palette.len();
+        println!("Selected {:?}", self.palette[self.selected]);
+    }
 }
@@ -1,10 +1,184 @@
 use crate::common::*;
 use crate::config::*;
+use crate::dag::*;
 use crate::event::*;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 use vulkano::command_buffer::{AutoCommandBuffer, AutoCommandBufferBuilder};
 
+/// Free space in the tree buffer, as sorted (start, end) ranges
+pub struct Spaces(Vec<(usize, usize)>);
+
+impl Spaces {
+    pub fn new(start: usize, end: usize) -> Self {
+        Spaces(vec![(start, end)])
+    }
+
+    /// Finds room for `size` slots, returning (start, end)
+    pub fn alloc(&mut self, size: usize) -> Option<(usize, usize)> {
+        for i in 0..self.0.len() {
+            let (space_start, space_end) = self.0[i];
+            let space_size = space_end - space_start;
+            if space_size == size {
+                // It fits EXACTLY, so just remove this space
+                self.0.remove(i);
+                return Some((space_start, space_end));
+            }
+            if space_size > size {
+                // It fits, so we can shrink this space
+                // We'll put the new thing at the start
+                self.0[i] = (space_start + size, space_end);
+                return Some((space_start, space_start + size));
+            }
+
+            // This one doesn't fit, so move on to the next space
+        }
+        None
+    }
+
+    /// Makes the slots from `start` to `end` available again
+    pub fn free(&mut self, start: usize, end: usize) {
+        for i in 0..self.0.len() {
+            let (space_start, space_end) = self.0[i];
+
+            if space_start == end {
+                // This space was just after what we're freeing, so we can just extend it backwards to fill the space
+                self.0[i] = (start, space_end);
+                return;
+            }
+            if space_end == start {
+                // What we're freeing was just after this space, so we can extend the space forwards
+                self.0[i] = (space_start, end);
+                // It might join up with the next space now
+                if i + 1 < self.0.len() && self.0[i + 1].0 == end {
+                    self.0[i].1 = self.0.remove(i + 1).1;
+                }
+                return;
+            }
+
+            if space_start > end {
+                // This space is after what we're freeing, so we'll put our new space here. It's like insertion sort
+                self.0.insert(i, (start, end));
+                return;
+            }
+
+            // This space is before what we're freeing, so we'll keep going until we find the right position
+        }
+        self.0.push((start, end));
+    }
+}
+
+/// Introduces us to the server, and waits for it to let us in.
+/// Returns our player id, where we spawn and the view distance the server will give us, or why we couldn't join.
+pub fn handshake(
+    conn: &Connection,
+    config: &ClientConfig,
+) -> Result<(usize, Vector3<f32>, usize), String> {
+    conn.send(Message::Hello {
+        version: PROTOCOL_VERSION,
+        name: config.name.clone(),
+        draw_chunks: config.game_config.draw_chunks,
+    })
+    .ok_or("Disconnected from server")?;
+
+    let timer = std::time::Instant::now();
+    while timer.elapsed() < std::time::Duration::from_secs(30) {
+        match conn.recv() {
+            Some(Message::Welcome {
+                id,
+                pos,
+                seed,
+                materials,
+                draw_chunks,
+            }) => {
+                if materials != Material::table() {
+                    return Err("The server has different materials than we do".to_string());
+                }
+                println!("Joined as player {}, with seed {}", id, seed);
+                return Ok((id, pos, draw_chunks));
+            }
+            Some(Message::Rejected(reason)) => return Err(reason),
+            Some(Message::Leave) => return Err("Disconnected from server".to_string()),
+            Some(m) => return Err(format!("Expected a Welcome, got {:?}", m)),
+            None => std::thread::sleep(std::time::Duration::from_millis(10)),
+        }
+    }
+    Err("The server didn't answer".to_string())
+}
+
+/// Where the other players are, from the server's updates.
+/// Each player moves smoothly from where we were drawing them to their new position over the time until the next update.
+#[derive(Default)]
+pub struct RemotePlayers {
+    /// (when we got the update, where they were, where they are now) for each player id
+    players: HashMap<usize, (Instant, PlayerState, PlayerState)>,
+}
+
+impl RemotePlayers {
+    /// Replaces everybody with the players in a `Message::Players`
+    pub fn update(&mut self, states: Vec<PlayerState>) {
+        let now = Instant::now();
+        let players = states
+            .into_iter()
+            .map(|next| {
+                let prev = self.get(next.id, now).unwrap_or(next);
+                (next.id, (now, prev, next))
+            })
+            .collect();
+        self.players = players;
+    }
+
+    /// Where player `id` should be drawn at `now`
+    fn get(&self, id: usize, now: Instant) -> Option<PlayerState> {
+        let (time, prev, next) = self.players.get(&id)?;
+        let a = (now.saturating_duration_since(*time).as_secs_f32()
+            / PLAYER_UPDATE_INTERVAL.as_secs_f32())
+        .min(1.0);
+        Some(PlayerState {
+            id,
+            pos: prev.pos + (next.pos - prev.pos) * a,
+            dir: prev.dir + (next.dir - prev.dir) * a,
+        })
+    }
+
+    /// Where all the players should be drawn at `now`
+    pub fn states(&self, now: Instant) -> Vec<PlayerState> {
+        self.players
+            .keys()
+            .filter_map(|&id| self.get(id, now))
+            .collect()
+    }
+}
+
+/// The time of day, from the server's `Message::Time`
+#[derive(Default)]
+pub struct DayTime {
+    day: f64,
+    speed: f64,
+    /// When we got the last update
+    at: Option<Instant>,
+}
+
+impl DayTime {
+    pub fn update(&mut self, day: f64, speed: f64) {
+        self.day = day;
+        self.speed = speed;
+        self.at = Some(Instant::now());
+    }
+
+    /// How far through the day it is at `now`, from 0 to 1, where 0 is noon
+    pub fn day(&self, now: Instant) -> f64 {
+        let elapsed = self
+            .at
+            .map_or(0.0, |at| now.saturating_duration_since(at).as_secs_f64());
+        (self.day + self.speed * elapsed).fract()
+    }
+}
+
+/// Chunks get this many extra slots in the tree buffer so they can grow without moving
+const CHUNK_SLACK: usize = 64 * 8;
+
 pub struct ClientWorld {
     conn: Connection,
     device: Arc<vulkano::device::Device>,
@@ -14,59 +188,143 @@ pub struct ClientWorld {
     pub root_size: f32,
     pub root: Vec<u32>, // The root structure. Points to chunks, gets buffer in the map
     pub map: HashMap<Vector3<i32>, (usize, usize)>, // (start, end)
-    spaces: Vec<(usize, usize)>, // (start, end)
+    spaces: Spaces,
+    /// In DAG mode, the subtrees shared between chunks in the tree buffer
+    pool: Option<SubtreePool>,
+    /// In DAG mode, the shared subtrees each chunk is using, which we release when it's unloaded.
+    /// We can't get them from the chunk in the `World`, because editing it stops it sharing them.
+    shared: HashMap<Vector3<i32>, Vec<Arc<[u32]>>>,
     pub tree_buffer: Arc<vulkano::buffer::DeviceLocalBuffer<[u32]>>,
     upload: vulkano::buffer::CpuBufferPool<u32>,
     config: Arc<ClientConfig>,
     reader_id: ReaderId<Event>,
+    /// The id to give the next edit we send to the server
+    next_edit: u32,
+    /// Edits we've made locally that the server hasn't answered yet: (id, position, new block, old block)
+    pending: Vec<(u32, Vector3<i32>, Block, Block)>,
+    /// Whether chunks have been loaded or unloaded since we last updated the `MemoryStats`
+    stats_dirty: bool,
+    /// Whether the server told us to leave
+    left: bool,
 }
 
 impl<'a> System<'a> for ClientWorld {
     type SystemData = (
-        WriteExpect<'a, crate::world::World>,
+        WriteExpect<'a, crate::world::World<SharedChunk>>,
         Write<'a, EventChannel<Event>>,
+        Write<'a, MemoryStats>,
+        Write<'a, RemotePlayers>,
+        Write<'a, DayTime>,
     );
 
-    fn run(&mut self, (mut world, mut events): Self::SystemData) {
+    fn run(
+        &mut self,
+        (mut world, mut events, mut stats, mut remote, mut day_time): Self::SystemData,
+    ) {
         let mut new_pos = None;
+        // Chunks we've changed locally, which need to be uploaded again
+        let mut edited = Vec::new();
         for event in events.read(&mut self.reader_id) {
             match event {
-                Event::PlayerMove(x) => {
-                    new_pos = Some(*x);
+                Event::PlayerMove(x, dir) => {
+                    new_pos = Some((*x, *dir));
+                }
+                Event::SetBlock(pos, block) => {
+                    // Show the edit straight away, and undo it later if the server rejects it
+                    let k = pos.map(|x| x as f32 + 0.5);
+                    if let Some(old) = world.get_block(k) {
+                        let id = self.next_edit;
+                        self.next_edit = self.next_edit.wrapping_add(1);
+                        world.set_block(k, *block);
+                        edited.push(world_to_chunk(k));
+                        self.pending.push((id, *pos, *block, old));
+                        self.conn.send(Message::SetBlock(id, *pos, *block));
+                    }
+                }
+                Event::Chat(text) => {
+                    self.conn.send(Message::Chat(text.clone()));
                 }
                 Event::Quit => {
-                    self.conn
-                        .send(Message::Leave)
-                        .expect("Disconnected from server");
+                    // If the server's already gone there's no one to tell
+                    if !self.left {
+                        self.conn.send(Message::Leave);
+                    }
                 }
                 _ => (),
             }
         }
-        if let Some(x) = new_pos {
+        if let Some((x, dir)) = new_pos {
             self.player = x;
-            self.conn.send(Message::PlayerMove(x));
+            self.conn.send(Message::PlayerMove(x, dir));
         }
-        if let Some(m) = self.conn.recv() {
-            // Only load chunks once per frame
+        let mut chunks = Vec::new();
+        while let Some(m) = self.conn.recv() {
             match m {
-                Message::Chunks(chunks) => {
+                Message::Chunks(c) => {
                     // println!(
                     //     "Requested load of {} chunks: \n{:?}",
                     //     chunks.len(),
                     //     chunks.iter().map(|x| x.0).collect::<Vec<Vector3<i32>>>()
                     // );
-
-                    let cmd = self.load_chunks(chunks, &mut world);
-                    events.single_write(Event::Submit(Once::new((
-                        cmd,
-                        self.origin,
-                        self.root_size,
-                        self.map.clone(),
-                    ))));
+                    chunks = c;
+                    // Only load chunks once per frame
+                    break;
+                }
+                Message::Players(states) => remote.update(states),
+                Message::Time { day, speed } => day_time.update(day, speed),
+                Message::Chat(line) => println!("{}", line),
+                Message::Teleport(pos) => events.single_write(Event::Teleport(pos)),
+                Message::EditResult(id, accepted) => {
+                    if let Some(i) = self.pending.iter().position(|x| x.0 == id) {
+                        let (_, pos, _, old) = self.pending.remove(i);
+                        if !accepted {
+                            // If there's a later edit to the same block, it becomes the one to undo
+                            match self.pending.iter_mut().find(|x| x.1 == pos) {
+                                Some(later) => later.3 = old,
+                                None => {
+                                    let k = pos.map(|x| x as f32 + 0.5);
+                                    if world.chunk(world_to_chunk(k)).is_some() {
+                                        world.set_block(k, old);
+                                        edited.push(world_to_chunk(k));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                Message::Leave => {
+                    // The event loop stops when it sees this, so everything gets cleaned up properly
+                    println!("Disconnected from server");
+                    self.left = true;
+                    events.single_write(Event::Quit);
                 }
                 _ => (),
             }
         }
+        // The server hasn't seen our pending edits yet, so keep them on top of its chunks
+        for (loc, chunk) in &mut chunks {
+            for (_, pos, block, _) in &self.pending {
+                let k = pos.map(|x| x as f32 + 0.5);
+                if world_to_chunk(k) == *loc {
+                    chunk.set_block(k - chunk_to_world(*loc), BLOCK_LEVEL, *block);
+                }
+            }
+        }
+        for loc in edited {
+            if !chunks.iter().any(|x| x.0 == loc) {
+                if let Some(chunk) = world.chunk(loc) {
+                    chunks.push((loc, chunk.to_chunk()));
+                }
+            }
+        }
+        if !chunks.is_empty() {
+            let cmd = self.load_chunks(chunks, &mut world);
+            events.single_write(Event::Submit(Once::new((cmd, self.origin, self.root_size))));
+        }
+        if self.stats_dirty {
+            *stats = self.memory_stats(&world);
+            self.stats_dirty = false;
+        }
     }
 }
 
@@ -80,6 +338,13 @@ impl ClientWorld {
         reader_id: ReaderId<Event>,
     ) -> Self {
         let start_len = 3_200_000; // = 12 MB
+                                   // The root is at most 2 * draw_chunks + 1 chunks across, and the shader's stack only has room for so many levels of it
+        let root_chunks = 2 * config.game_config.draw_chunks + 1;
+        assert!(
+            root_chunks <= 1 << MAX_ROOT_LEVELS,
+            "draw_chunks can't be more than {}",
+            ((1 << MAX_ROOT_LEVELS) - 1) / 2
+        );
         let mut max_root_size = config.game_config.draw_chunks.pow(3);
         let mut last = max_root_size * 8;
         while last > 0 {
@@ -88,6 +353,18 @@ impl ClientWorld {
         }
         println!("Max root size = {}", max_root_size);
 
+        // In DAG mode, shared subtrees go in the second half of the buffer, after all the chunks
+        let chunks_start = max_root_size as usize * 8;
+        let (spaces, pool) = if config.dag {
+            let mid = (chunks_start + start_len) / 2;
+            (
+                Spaces::new(chunks_start, mid),
+                Some(SubtreePool::new(mid, start_len)),
+            )
+        } else {
+            (Spaces::new(chunks_start, start_len), None)
+        };
+
         ClientWorld {
             conn,
             device: device.clone(),
@@ -97,7 +374,9 @@ impl ClientWorld {
             root_size: 8.0, //CHUNK_NUM.max() as f32 * CHUNK_SIZE,
             root: vec![0; 8],
             map: HashMap::new(),
-            spaces: vec![(max_root_size as usize * 8, start_len)],
+            spaces,
+            pool,
+            shared: HashMap::new(),
             tree_buffer: vulkano::buffer::DeviceLocalBuffer::array(
                 device.clone(),
                 start_len,
@@ -112,6 +391,10 @@ impl ClientWorld {
             upload: vulkano::buffer::CpuBufferPool::upload(device.clone()),
             config,
             reader_id,
+            next_edit: 0,
+            pending: Vec::new(),
+            stats_dirty: false,
+            left: false,
         }
     }
 
@@ -120,7 +403,7 @@ impl ClientWorld {
     pub fn load_chunks<'a>(
         &mut self,
         chunks: Vec<(Vector3<i32>, Chunk)>,
-        world: &mut WriteExpect<'a, crate::world::World>,
+        world: &mut WriteExpect<'a, crate::world::World<SharedChunk>>,
     ) -> AutoCommandBuffer {
         let mut cmd = AutoCommandBufferBuilder::primary_one_time_submit(
             self.device.clone(),
@@ -148,10 +431,10 @@ impl ClientWorld {
     fn upload_chunk(
         &mut self,
         r: std::ops::Range<usize>,
-        data: Chunk,
+        data: Vec<u32>,
         builder: AutoCommandBufferBuilder,
     ) -> AutoCommandBufferBuilder {
-        let chunk = self.upload.chunk(data.0).unwrap();
+        let chunk = self.upload.chunk(data).unwrap();
         let view = vulkano::buffer::BufferSlice::from_typed_buffer_access(self.tree_buffer.clone())
             .slice(r)
             .unwrap();
@@ -160,56 +443,88 @@ impl ClientWorld {
 
     /// Loads a chunk in at position `idx` in world-space (divided by CHUNK_SIZE)
     /// Will automatically unload the chunk that was previously there.
+    /// That's also how a low-detail chunk gets replaced when the server sends the full version.
     /// Uploads this chunk to GPU memory, and returns a command buffer to copy it to the right location.
     pub fn load<'a>(
         &mut self,
         idx: Vector3<i32>,
         chunk: Chunk,
         builder: AutoCommandBufferBuilder,
-        world: &mut WriteExpect<'a, crate::world::World>,
+        world: &mut WriteExpect<'a, crate::world::World<SharedChunk>>,
     ) -> AutoCommandBufferBuilder {
+        // A bad chunk could hang or crash the shader, so don't upload it
+        if let Err(e) = chunk.validate() {
+            println!("WARNING: got an invalid chunk at {:?}: {}", idx, e);
+            return builder;
+        }
+
         // Unload the previous chunk at this location, if there was one
         self.unload(idx, world);
 
-        // We need this much space
-        // We add space for 64 nodes to allow for the chunk to grow without moving. We'll move it if it goes past 32 - TODO
-        let size = chunk.len() + 64 * 8;
+        let (data, builder, chunk) = match &mut self.pool {
+            Some(pool) => {
+                // Share the subtrees, and only upload the ones that are new.
+                // Edits go through here too, so the chunk doesn't need any extra space.
+                // The GPU's copy points to the subtrees in the pool, and ours keeps the same `Arc`s the pool does.
+                let (top, subtrees) = chunk.split(SHARE_LEVEL);
+                let mut gpu_top = top.clone();
+                let (start, _) = self.spaces.alloc(top.len()).unwrap_or_else(|| {
+                    panic!(
+                        "Could not find space for chunk {:?}, size {}!",
+                        idx,
+                        top.len()
+                    )
+                });
 
-        // Find a space
-        let mut i = 0;
-        let (start, end) = loop {
-            let (space_start, space_end) = self.spaces[i];
-            let space_size = space_end - space_start;
-            if space_size == size {
-                // Our chunk fits EXACTLY, so just remove this space
-                self.spaces.remove(i);
-                break (space_start, space_end);
-            }
-            if space_size > size {
-                // Our chunk fits, so we can shrink this space
-                // We'll put our new chunk at the start
-                self.spaces[i] = (space_start + size, space_end);
-                break (space_start, space_start + size);
+                let mut builder = builder;
+                let mut shared = Vec::new();
+                let mut cpu_subtrees = Vec::new();
+                for (slot, parent, subtree) in subtrees {
+                    let (subtree, sub_start, new) = pool.insert(subtree);
+                    gpu_top[slot] = ((sub_start - (start + parent)) << 1 | 1) as u32;
+                    if new {
+                        let chunk = self.upload.chunk(subtree.iter().cloned()).unwrap();
+                        let view = vulkano::buffer::BufferSlice::from_typed_buffer_access(
+                            self.tree_buffer.clone(),
+                        )
+                        .slice(sub_start..sub_start + subtree.len())
+                        .unwrap();
+                        builder = builder.copy_buffer(chunk, view).unwrap();
+                    }
+                    cpu_subtrees.push((slot, parent, Arc::clone(&subtree)));
+                    shared.push(subtree);
+                }
+                self.shared.insert(idx, shared);
+                self.map.insert(idx, (start, start + gpu_top.len()));
+                (gpu_top, builder, SharedChunk::new(top, cpu_subtrees))
             }
+            None => {
+                // We need this much space
+                // We add space for 64 nodes to allow for the chunk to grow without moving. We'll move it if it goes past 32 - TODO
+                let size = chunk.len() + CHUNK_SLACK;
 
-            // This one doesn't fit, so move on to the next space
-            i += 1;
-            if i >= self.spaces.len() {
-                // We're to the end of `spaces`, so this chunk can't fit anywhere
-                panic!("Could not find space for chunk {:?}, size {}!", idx, size);
+                // Find a space
+                let (start, end) = self.spaces.alloc(size).unwrap_or_else(|| {
+                    // This chunk can't fit anywhere
+                    panic!("Could not find space for chunk {:?}, size {}!", idx, size)
+                });
+
+                // Add the 64 empty nodes here
+                let mut chunk_gpu = chunk.0.clone();
+                chunk_gpu.append(&mut vec![0; CHUNK_SLACK]);
+
+                self.map.insert(idx, (start, end));
+                (chunk_gpu, builder, SharedChunk::from(chunk))
             }
         };
+        let (start, end) = self.map[&idx];
 
-        // Add the 64 empty nodes here
-        let mut chunk_gpu = chunk.clone();
-        chunk_gpu.append(&mut vec![0; 64 * 8]);
-
-        // Add to map & chunks
+        // Add to chunks
         world.add_chunk(idx, chunk);
-        self.map.insert(idx, (start, end));
+        self.stats_dirty = true;
 
         // Upload to GPU
-        self.upload_chunk(start..end, chunk_gpu, builder)
+        self.upload_chunk(start..end, data, builder)
     }
 
     /// Unload the chunk at position `idx` in world space.
@@ -217,41 +532,43 @@ impl ClientWorld {
     pub fn unload<'a>(
         &mut self,
         idx: Vector3<i32>,
-        world: &mut WriteExpect<'a, crate::world::World>,
+        world: &mut WriteExpect<'a, crate::world::World<SharedChunk>>,
     ) {
         if let Some((start, end)) = self.map.remove(&idx) {
             world.remove_chunk(idx);
-
-            // Add a space
-            for i in 0..self.spaces.len() {
-                let (space_start, space_end) = self.spaces[i];
-
-                if space_start == end {
-                    // This space was at the end of our chunk, so we can just extend it backwards to fill the space
-                    self.spaces[i] = (start, space_end);
-                    break;
-                }
-                if space_end == start {
-                    // Our chunk was just after this space, so we can extend the space forwards
-                    self.spaces[i] = (space_start, end);
-                    break;
+            self.spaces.free(start, end);
+            self.stats_dirty = true;
+            if let Some(pool) = &mut self.pool {
+                for subtree in self.shared.remove(&idx).unwrap_or_default() {
+                    pool.release(&subtree);
                 }
-
-                if space_start > end {
-                    // This space is after our chunk, so we'll put our new space here. It's like insertion sort
-                    self.spaces.insert(i, (start, end));
-                    break;
-                }
-
-                // This space is before our chunk, so we'll keep going until we find the right position
             }
 
             // We don't have to touch GPU memory, because we aren't necessarily replacing this chunk with anything
         }
     }
 
+    /// How much of the tree buffer the chunks are using, compared to storing them all separately.
+    /// This goes through every loaded chunk, so it only runs when the chunks change.
+    pub fn memory_stats(&self, world: &crate::world::World<SharedChunk>) -> MemoryStats {
+        let chunk_slots = self.map.values().map(|(start, end)| end - start).sum();
+        let flat_slots = self
+            .map
+            .keys()
+            .filter_map(|k| world.chunk(*k))
+            .map(|x| x.len() + CHUNK_SLACK)
+            .sum();
+        MemoryStats {
+            chunks: self.map.len(),
+            chunk_slots,
+            shared_slots: self.pool.as_ref().map_or(0, |x| x.size()),
+            shared_subtrees: self.pool.as_ref().map_or(0, |x| x.len()),
+            flat_slots,
+        }
+    }
+
     /// Unloads chunks that are too far away
-    fn prune_chunks<'a>(&mut self, world: &mut WriteExpect<'a, crate::world::World>) {
+    fn prune_chunks<'a>(&mut self, world: &mut WriteExpect<'a, crate::world::World<SharedChunk>>) {
         let c = world_to_chunk(self.player);
         for i in self.map.clone().keys() {
             if (c - i).map(|x| x as f32).norm() > self.config.game_config.draw_chunks as f32 {
@@ -261,7 +578,7 @@ impl ClientWorld {
     }
 
     /// Recreates the root node to incorporate newly loaded chunks
-    fn create_root<'a>(&mut self, world: &mut WriteExpect<'a, crate::world::World>) {
+    fn create_root<'a>(&mut self, world: &mut WriteExpect<'a, crate::world::World<SharedChunk>>) {
         // Find the extent of the root in each direction
         let k: Vec<_> = world.locs().cloned().collect();
         let l = k
@@ -319,3 +636,24 @@ impl ClientWorld {
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn day_wraps() {
+        let mut time = DayTime::default();
+        time.update(0.9, 0.1);
+        let at = time.at.unwrap();
+        assert!((time.day(at) - 0.9).abs() < 1e-9);
+        assert!((time.day(at + Duration::from_secs(2)) - 0.1).abs() < 1e-9);
+        assert!((time.day(at + Duration::from_secs(14)) - 0.3).abs() < 1e-9);
+
+        // A frozen clock stays where it is
+        time.update(0.3, 0.0);
+        assert_eq!(time.day(at + Duration::from_secs(100)), 0.3);
+    }
+}
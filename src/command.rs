@@ -0,0 +1,287 @@
+//! Commands that players type into chat, like `/tp 0 40 0`, or admins type into the server console.
+//! This just parses them - the server runs them.
+use crate::common::*;
+use enum_iterator::IntoEnumIterator;
+use std::str::FromStr;
+
+/// Who's allowed to run a command. Admins can do everything players can, and the console can do everything
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Permission {
+    Player,
+    Admin,
+    /// Whoever's typing into the server process
+    Console,
+}
+
+/// Where `/tp` sends someone
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Pos(Vector3<f32>),
+    /// To where this player is
+    Player(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeCommand {
+    /// Says what time it is
+    Query,
+    /// Sets the time of day, from 0 to 1, where 0 is noon
+    Set(f64),
+    Freeze,
+    Unfreeze,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Help,
+    /// Sends a player somewhere. If `who` is None, it's whoever ran the command
+    Tp {
+        who: Option<String>,
+        to: Target,
+    },
+    SetBlock(Vector3<i32>, Material),
+    /// Fills the box between these two corners, including both of them
+    Fill(Vector3<i32>, Vector3<i32>, Material),
+    Time(TimeCommand),
+    Seed,
+    Save,
+    /// Disconnects a player, with an optional reason
+    Kick(String, Option<String>),
+    /// Sends a chat message to everybody
+    Say(String),
+    /// Shows how the server's doing
+    Status,
+    /// Saves the world and stops the server
+    Stop,
+}
+
+/// Every command's usage, and who can run it, for `/help` and error messages
+const USAGE: &[(&str, &str, Permission)] = &[
+    ("help", "/help", Permission::Player),
+    (
+        "tp",
+        "/tp [player] <x> <y> <z> or /tp [player] <other player>",
+        Permission::Admin,
+    ),
+    (
+        "setblock",
+        "/setblock <x> <y> <z> <material>",
+        Permission::Admin,
+    ),
+    (
+        "fill",
+        "/fill <x1> <y1> <z1> <x2> <y2> <z2> <material>",
+        Permission::Admin,
+    ),
+    (
+        "time",
+        "/time [set <hh:mm|noon|midnight|sunrise|sunset> | freeze | unfreeze]",
+        Permission::Admin,
+    ),
+    ("seed", "/seed", Permission::Player),
+    ("save", "/save", Permission::Admin),
+    ("kick", "/kick <player> [reason]", Permission::Admin),
+    ("say", "/say <message>", Permission::Player),
+    ("status", "/status", Permission::Admin),
+    ("stop", "/stop", Permission::Console),
+];
+
+/// Lists the commands someone with `perm` can run
+pub fn help(perm: Permission) -> String {
+    let lines: Vec<_> = USAGE
+        .iter()
+        .filter(|(_, _, p)| *p <= perm)
+        .map(|(_, usage, _)| *usage)
+        .collect();
+    format!("Commands:\n{}", lines.join("\n"))
+}
+
+fn usage(name: &str) -> String {
+    let usage = USAGE.iter().find(|(n, _, _)| *n == name).unwrap().1;
+    format!("Usage: {}", usage)
+}
+
+fn num<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("'{}' isn't a number", s))
+}
+
+fn vector<T: FromStr + na::Scalar>(args: &[&str]) -> Result<Vector3<T>, String> {
+    Ok(Vector3::new(num(args[0])?, num(args[1])?, num(args[2])?))
+}
+
+/// Finds a material by name, ignoring case
+fn material(s: &str) -> Result<Material, String> {
+    Material::into_enum_iter()
+        .filter(|&m| m != Material::Wrong)
+        .find(|m| format!("{:?}", m).eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("There's no material called '{}'", s))
+}
+
+/// Parses a time of day, like "18:30" or "noon", into how far through the day it is, where 0 is noon
+fn time_of_day(s: &str) -> Result<f64, String> {
+    let hours = match s {
+        "noon" => 12.0,
+        "midnight" => 0.0,
+        "sunrise" => 6.0,
+        "sunset" => 18.0,
+        _ => {
+            let bad = || format!("'{}' isn't a time, try something like 18:30", s);
+            let mut parts = s.split(':');
+            let h: u32 = parts.next().and_then(|x| x.parse().ok()).ok_or_else(bad)?;
+            let m: u32 = parts.next().unwrap_or("0").parse().map_err(|_| bad())?;
+            if h >= 24 || m >= 60 || parts.next().is_some() {
+                return Err(bad());
+            }
+            h as f64 + m as f64 / 60.0
+        }
+    };
+    Ok(((hours - 12.0) / 24.0).rem_euclid(1.0))
+}
+
+/// Formats a time of day as "hh:mm"
+pub fn clock(day: f64) -> String {
+    let minutes = ((day * 24.0 + 12.0) * 60.0).round() as u32 % (24 * 60);
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+impl Command {
+    /// Parses a line of chat that starts with a '/'
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let line = line.strip_prefix('/').unwrap_or(line);
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+
+        let bad = || Err(usage(name));
+        match name {
+            "help" => Ok(Command::Help),
+            "tp" => match args.len() {
+                1 => Ok(Command::Tp {
+                    who: None,
+                    to: Target::Player(args[0].to_string()),
+                }),
+                2 => Ok(Command::Tp {
+                    who: Some(args[0].to_string()),
+                    to: Target::Player(args[1].to_string()),
+                }),
+                3 => Ok(Command::Tp {
+                    who: None,
+                    to: Target::Pos(vector(&args)?),
+                }),
+                4 => Ok(Command::Tp {
+                    who: Some(args[0].to_string()),
+                    to: Target::Pos(vector(&args[1..])?),
+                }),
+                _ => bad(),
+            },
+            "setblock" if args.len() == 4 => {
+                Ok(Command::SetBlock(vector(&args)?, material(args[3])?))
+            }
+            "fill" if args.len() == 7 => Ok(Command::Fill(
+                vector(&args)?,
+                vector(&args[3..])?,
+                material(args[6])?,
+            )),
+            "time" => match args.as_slice() {
+                [] => Ok(Command::Time(TimeCommand::Query)),
+                ["set", t] => Ok(Command::Time(TimeCommand::Set(time_of_day(t)?))),
+                ["freeze"] => Ok(Command::Time(TimeCommand::Freeze)),
+                ["unfreeze"] => Ok(Command::Time(TimeCommand::Unfreeze)),
+                _ => bad(),
+            },
+            "seed" => Ok(Command::Seed),
+            "save" => Ok(Command::Save),
+            "kick" if !args.is_empty() => Ok(Command::Kick(
+                args[0].to_string(),
+                if args.len() > 1 {
+                    Some(args[1..].join(" "))
+                } else {
+                    None
+                },
+            )),
+            "say" if !args.is_empty() => Ok(Command::Say(args.join(" "))),
+            "status" => Ok(Command::Status),
+            "stop" => Ok(Command::Stop),
+            _ if USAGE.iter().any(|(n, _, _)| *n == name) => bad(),
+            _ => Err(format!("Unknown command /{}, try /help", name)),
+        }
+    }
+
+    /// Who's allowed to run this command
+    pub fn permission(&self) -> Permission {
+        let name = match self {
+            Command::Help => "help",
+            Command::Tp { .. } => "tp",
+            Command::SetBlock(..) => "setblock",
+            Command::Fill(..) => "fill",
+            Command::Time(_) => "time",
+            Command::Seed => "seed",
+            Command::Save => "save",
+            Command::Kick(..) => "kick",
+            Command::Say(_) => "say",
+            Command::Status => "status",
+            Command::Stop => "stop",
+        };
+        USAGE.iter().find(|(n, _, _)| *n == name).unwrap().2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Command::parse("/tp 1 2.5 -3"),
+            Ok(Command::Tp {
+                who: None,
+                to: Target::Pos(Vector3::new(1.0, 2.5, -3.0))
+            })
+        );
+        assert_eq!(
+            Command::parse("/fill 0 0 0 4 4 4 stone"),
+            Ok(Command::Fill(
+                Vector3::zeros(),
+                Vector3::repeat(4),
+                Material::Stone
+            ))
+        );
+        assert_eq!(
+            Command::parse("/kick Bob too loud"),
+            Ok(Command::Kick(
+                "Bob".to_string(),
+                Some("too loud".to_string())
+            ))
+        );
+        assert_eq!(
+            Command::parse("/say hello   everyone"),
+            Ok(Command::Say("hello everyone".to_string()))
+        );
+        assert!(Command::parse("/say").is_err());
+        assert_eq!(Command::parse("/status"), Ok(Command::Status));
+        assert_eq!(Command::parse("/stop"), Ok(Command::Stop));
+        assert!(Command::parse("/setblock 0 0 wrong").is_err());
+        assert!(Command::parse("/setblock 0 0 0 wrong").is_err());
+        assert!(Command::parse("/dance").is_err());
+    }
+
+    #[test]
+    fn permissions() {
+        assert_eq!(Command::Say(String::new()).permission(), Permission::Player);
+        assert_eq!(Command::Status.permission(), Permission::Admin);
+        assert_eq!(Command::Stop.permission(), Permission::Console);
+        // Only the console can see /stop
+        assert!(help(Permission::Console).contains("/stop"));
+        assert!(!help(Permission::Admin).contains("/stop"));
+        assert!(!help(Permission::Player).contains("/status"));
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(time_of_day("noon"), Ok(0.0));
+        assert_eq!(time_of_day("18:00"), Ok(0.25));
+        assert_eq!(clock(time_of_day("06:30").unwrap()), "06:30");
+        assert!(time_of_day("25:00").is_err());
+    }
+}
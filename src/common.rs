@@ -1,19 +1,56 @@
+pub use crate::block::*;
 pub use crate::material::Material;
 pub use crate::octree::*;
+pub use crate::shape::*;
 pub use na::{Point3, Vector3};
 pub use nalgebra as na;
 pub use num_traits::Zero;
+use serde::{Deserialize, Serialize};
 pub use specs::prelude::*;
 pub use specs::shrev::{EventChannel, ReaderId};
 pub use std::collections::HashMap;
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
 use std::sync::mpsc::*;
 use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "client")]
 pub use vulkano::half::prelude::*;
 
 pub const CHUNK_SIZE: f32 = 16.0;
 
 pub const REGION_SIZE: i32 = 4;
 
+/// Bump this whenever `Message` changes, so clients and servers know when they can't understand each other
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// How many times a second the server updates the world
+pub const TICK_RATE: u64 = 20;
+/// How long each server tick should take
+pub const TICK_TIME: Duration = Duration::from_millis(1000 / TICK_RATE);
+
+/// How often the server tells clients where the other players are, which is once a tick
+pub const PLAYER_UPDATE_INTERVAL: Duration = TICK_TIME;
+
+/// The port `quanta-server` listens on if it isn't given an address
+pub const DEFAULT_PORT: u16 = 24242;
+
+/// How far away the player can break and place blocks, in meters
+pub const REACH: f32 = 12.0;
+
+/// The size of the player's collision box: 0.6 x 1.8 x 0.6
+pub const PLAYER_SIZE: [f32; 3] = [0.6, 1.8, 0.6];
+/// How far above the bottom of the player's box the camera is
+pub const EYE_HEIGHT: f32 = 1.6;
+
+/// The collision box of a player whose eyes are at `eye`
+pub fn player_aabb(eye: Vector3<f32>) -> Aabb {
+    let size = Vector3::from(PLAYER_SIZE);
+    let min = eye - Vector3::new(size.x * 0.5, EYE_HEIGHT, size.z * 0.5);
+    Aabb::new(min, min + size)
+}
+
 pub fn radians(degrees: f32) -> f32 {
     std::f32::consts::PI / 180.0 * degrees
 }
@@ -47,8 +84,9 @@ pub fn world_to_chunk(world: Vector3<f32>) -> Vector3<i32> {
 pub fn region_to_chunk(chunk: Vector3<i32>) -> Vector3<i32> {
     chunk.map(|x| x * REGION_SIZE)
 }
-pub fn chunk_to_region(world: Vector3<i32>) -> Vector3<i32> {
-    world.map(|x| (x + REGION_SIZE / 2) / REGION_SIZE)
+/// The region containing `chunk`, which is the same one `in_region` gives an index into
+pub fn chunk_to_region(chunk: Vector3<i32>) -> Vector3<i32> {
+    chunk.map(|x| x.div_euclid(REGION_SIZE))
 }
 pub fn in_region(chunk: Vector3<i32>) -> usize {
     let v = chunk.map(|x| ((x % REGION_SIZE) + REGION_SIZE) as usize % REGION_SIZE as usize);
@@ -57,9 +95,15 @@ pub fn in_region(chunk: Vector3<i32>) -> usize {
 
 pub enum Connection {
     Local(Sender<Message>, Receiver<Message>),
-    // TODO some sort of buffered TCP stream inplementation of Connection
+    /// A connection to another process over TCP.
+    /// Messages are written and read on their own threads, so this works just like `Local` and never blocks.
+    /// If the other end goes away, we get a `Message::Leave`.
+    Tcp(Sender<Message>, Receiver<Message>),
 }
 
+/// The biggest message we'll accept over TCP, so a bad length doesn't make us allocate forever
+const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
 impl Connection {
     /// Create a two new Local connections - (client, server)
     pub fn local() -> (Connection, Connection) {
@@ -70,26 +114,138 @@ impl Connection {
         (client, server)
     }
 
+    /// Wraps a TCP stream in a Connection.
+    /// Each message is sent as a little-endian u32 length followed by that many bytes of bincode.
+    pub fn tcp(stream: TcpStream) -> std::io::Result<Connection> {
+        stream.set_nodelay(true)?;
+        let mut write = stream.try_clone()?;
+        let mut read = BufReader::new(stream);
+
+        let (to, from_us) = channel::<Message>();
+        thread::spawn(move || {
+            for m in from_us {
+                if let Err(e) = write_message(&mut write, &m) {
+                    println!("WARNING: couldn't send message: {}", e);
+                    break;
+                }
+            }
+            // We're done with the connection, so let the other end know
+            write.shutdown(Shutdown::Both).ok();
+        });
+
+        let (to_us, from) = channel();
+        thread::spawn(move || loop {
+            match read_message(&mut read) {
+                Ok(m) => {
+                    if to_us.send(m).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    if e.kind() != ErrorKind::UnexpectedEof {
+                        println!("WARNING: lost connection: {}", e);
+                    }
+                    to_us.send(Message::Leave).ok();
+                    break;
+                }
+            }
+        });
+
+        Ok(Connection::Tcp(to, from))
+    }
+
     /// Equivalent to Sender::send() but as an option
     pub fn send(&self, m: Message) -> Option<()> {
         match self {
-            Connection::Local(to, _from) => to.send(m).ok(),
+            Connection::Local(to, _from) | Connection::Tcp(to, _from) => to.send(m).ok(),
         }
     }
 
     /// Equivalent to Receiver::try_recv() but as an option - doesn't block
     pub fn recv(&self) -> Option<Message> {
         match self {
-            Connection::Local(_to, from) => from.try_recv().ok(),
+            Connection::Local(_to, from) | Connection::Tcp(_to, from) => from.try_recv().ok(),
         }
     }
 }
 
-#[derive(Debug)]
+fn write_message(w: &mut impl Write, m: &Message) -> std::io::Result<()> {
+    let buf = bincode::serialize(m).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    w.write_all(&(buf.len() as u32).to_le_bytes())?;
+    w.write_all(&buf)?;
+    w.flush()
+}
+
+fn read_message(r: &mut impl Read) -> std::io::Result<Message> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("message is too big: {} bytes", len),
+        ));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    bincode::deserialize(&buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Where another player is, and which way they're looking
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerState {
+    pub id: usize,
+    /// Where their eyes are
+    pub pos: Vector3<f32>,
+    pub dir: Vector3<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
-    PlayerMove(Vector3<f32>),
+    // The handshake goes first so it's encoded the same way in every version.
+    /// The first thing a client sends, before it can do anything else
+    Hello {
+        version: u32,
+        name: String,
+        /// The client's view distance, in chunks. The server might lower it
+        draw_chunks: usize,
+    },
+    /// The server's answer to `Hello` when the client can join
+    Welcome {
+        id: usize,
+        pos: Vector3<f32>,
+        seed: u32,
+        /// The id and name of every material the server knows about
+        materials: Vec<(u16, String)>,
+        /// How many chunks away the server will send us, which can be less than we asked for
+        draw_chunks: usize,
+    },
+    /// The server's answer to `Hello` when the client can't join, with the reason
+    Rejected(String),
+    /// Where the player's eyes are, and which way they're looking
+    PlayerMove(Vector3<f32>, Vector3<f32>),
+    /// The other players close enough to see, sent every `PLAYER_UPDATE_INTERVAL`
+    Players(Vec<PlayerState>),
+    /// The time of day, which the server sends every so often and whenever it changes.
+    /// Clients keep the clock running between updates.
+    Time {
+        /// How far through the day it is, from 0 to 1. 0 is noon
+        day: f64,
+        /// How many days pass per second, which is 0 if the time is frozen
+        speed: f64,
+    },
     Chunks(Vec<(Vector3<i32>, Chunk)>),
-    //SetBlock(Vector3<i32>, Material),
+    /// A request from a client to change the full-size block with its low corner here.
+    /// The first field is an id the client picks, which the server answers with `EditResult`.
+    SetBlock(u32, Vector3<i32>, Block),
+    /// The server's answer to `SetBlock`: whether it made the edit.
+    /// If it did, the changed chunk follows in a `Chunks` message; if not, the client should undo it.
+    EditResult(u32, bool),
+    /// From a client, something they typed in chat, which might be a command like "/tp 0 40 0".
+    /// From the server, a line of text to show the player, like someone else's chat or the result of a command.
+    Chat(String),
+    /// The server moving a player, with `/tp`
+    Teleport(Vector3<f32>),
     Leave,
 }
 
@@ -100,6 +256,10 @@ pub enum ChunkMessage {
     LoadChunks(Vec<Vector3<i32>>),
     // Chunks(Vec<(Vector3<i32>, Chunk)>),
     UnloadChunk(Vector3<i32>, Chunk),
+    /// Save a chunk that's been edited, but keep it loaded
+    SaveChunk(Vector3<i32>, Chunk),
+    /// Write everything that's been saved to disk now, instead of whenever it leaves the cache
+    Flush,
     Players(Vec<Vector3<f32>>),
 }
 
@@ -118,4 +278,48 @@ mod tests {
             (v - chunk_to_world(world_to_chunk(v))).norm()
         );
     }
+
+    #[test]
+    fn regions_dont_overlap() {
+        let mut seen = std::collections::HashSet::new();
+        for x in -8..8 {
+            for y in -8..8 {
+                for z in -8..8 {
+                    let chunk = Vector3::new(x, y, z);
+                    let region = chunk_to_region(chunk);
+                    assert!(
+                        seen.insert((region, in_region(chunk))),
+                        "{:?} has the same slot as another chunk",
+                        chunk
+                    );
+                    // The region starts at or before the chunk, and ends after it
+                    let start = region_to_chunk(region);
+                    assert!((chunk - start).iter().all(|&i| i >= 0 && i < REGION_SIZE));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let mut buf = std::io::Cursor::new(Vec::new());
+        write_message(&mut buf, &Message::Rejected("full".to_string())).unwrap();
+        write_message(&mut buf, &Message::Rejected("again".to_string())).unwrap();
+        buf.set_position(0);
+        // Two in a row, so we know each one reads exactly its own bytes
+        for expected in &["full", "again"] {
+            match read_message(&mut buf).unwrap() {
+                Message::Rejected(s) => assert_eq!(&s, expected),
+                m => panic!("Got the wrong message back: {:?}", m),
+            }
+        }
+    }
+
+    #[test]
+    fn message_too_big() {
+        let mut buf = ((MAX_MESSAGE_SIZE + 1) as u32).to_le_bytes().to_vec();
+        buf.extend(&[0; 16]);
+        let e = read_message(&mut std::io::Cursor::new(buf)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
 }
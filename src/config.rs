@@ -2,17 +2,47 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 /// Config for both the client and server
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct GameConfig {
     pub draw_chunks: usize, // The number of chunks to draw in every direction
     pub batch_size: usize,  // The number of chunks to load per batch
     pub save_chunks: bool,
+    pub lod_chunks: usize, // Chunks further away than this are sent with less detail
+    pub lod_levels: u32,   // The number of octree levels to remove from those chunks
+    pub seed: u32,         // The seed for terrain generation
+    pub day_length: f64,   // How long a day lasts, in seconds
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            draw_chunks: 16,
+            batch_size: 64,
+            save_chunks: true,
+            lod_chunks: 8,
+            lod_levels: 1,
+            seed: 1,
+            day_length: 24.0 * 60.0,
+        }
+    }
+}
+
+fn default_name() -> String {
+    "Player".to_string()
 }
 
 /// Config for just the client
 #[derive(Deserialize, Serialize)]
 pub struct ClientConfig {
+    #[serde(default)]
     pub keycodes: crate::input::KeyCodes,
+    /// Share identical subtrees between chunks, on the CPU and the GPU
+    #[serde(default)]
+    pub dag: bool,
+    /// What other players see us as
+    #[serde(default = "default_name")]
+    pub name: String,
 
     pub game_config: Arc<GameConfig>,
 }
@@ -0,0 +1,104 @@
+use crate::client_world::Spaces;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Chunks are split at this level, and the subtrees below it are shared.
+/// Level 3 nodes are 4x4x4 blocks, which is small enough that flat ground and solid stone repeat a lot.
+pub const SHARE_LEVEL: u32 = 3;
+
+/// The subtrees shared between chunks in DAG mode.
+/// Each distinct subtree is stored once in the tree buffer, and reference counted by the chunks using it.
+/// On the CPU, the `SharedChunk`s in the client's `World` hold the same `Arc`s as the pool, so there's only one copy there too.
+/// The pool's space has to come after all the chunks in the buffer, because pointers can only point forwards.
+pub struct SubtreePool {
+    spaces: Spaces,
+    /// Subtree -> (start in the tree buffer, number of references)
+    subtrees: HashMap<Arc<[u32]>, (usize, usize)>,
+    /// The number of slots all the subtrees take up
+    size: usize,
+}
+
+impl SubtreePool {
+    /// Create a pool that uses the slots from `start` to `end` in the tree buffer
+    pub fn new(start: usize, end: usize) -> Self {
+        SubtreePool {
+            spaces: Spaces::new(start, end),
+            subtrees: HashMap::new(),
+            size: 0,
+        }
+    }
+
+    /// Adds a reference to `subtree`, allocating space for it if it's new.
+    /// Returns the shared copy and where it starts in the tree buffer, and whether it still needs to be uploaded.
+    pub fn insert(&mut self, subtree: Vec<u32>) -> (Arc<[u32]>, usize, bool) {
+        if let Some((shared, &(start, refs))) = self.subtrees.get_key_value(&subtree[..]) {
+            let shared = Arc::clone(shared);
+            self.subtrees.insert(Arc::clone(&shared), (start, refs + 1));
+            return (shared, start, false);
+        }
+
+        let size = subtree.len();
+        let (start, _) = self
+            .spaces
+            .alloc(size)
+            .unwrap_or_else(|| panic!("Could not find space for shared subtree, size {}!", size));
+        let shared: Arc<[u32]> = subtree.into();
+        self.subtrees.insert(Arc::clone(&shared), (start, 1));
+        self.size += size;
+        (shared, start, true)
+    }
+
+    /// Removes a reference to `subtree`, and frees its space if nothing else is using it
+    pub fn release(&mut self, subtree: &Arc<[u32]>) {
+        let (start, refs) = self.subtrees[subtree];
+        if refs > 1 {
+            self.subtrees.insert(Arc::clone(subtree), (start, refs - 1));
+        } else {
+            self.subtrees.remove(subtree);
+            self.spaces.free(start, start + subtree.len());
+            self.size -= subtree.len();
+        }
+    }
+
+    /// The number of distinct subtrees in the pool
+    pub fn len(&self) -> usize {
+        self.subtrees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subtrees.is_empty()
+    }
+
+    /// The number of slots the pool is using
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// How much of the tree buffer the loaded chunks take up. Our own copy of the chunks shares subtrees the same way
+#[derive(Default, Clone, Copy, Debug)]
+pub struct MemoryStats {
+    pub chunks: usize,
+    /// Slots used by the chunks themselves, which doesn't include shared subtrees
+    pub chunk_slots: usize,
+    /// Slots used by shared subtrees
+    pub shared_slots: usize,
+    pub shared_subtrees: usize,
+    /// Slots the chunks would take up if each one was stored separately
+    pub flat_slots: usize,
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let used = self.chunk_slots + self.shared_slots;
+        write!(
+            f,
+            "{} chunks use {:.1} MB ({} shared subtrees), {:.1} MB stored separately ({:.0}%)",
+            self.chunks,
+            used as f64 * 4.0 / 1_000_000.0,
+            self.shared_subtrees,
+            self.flat_slots as f64 * 4.0 / 1_000_000.0,
+            used as f64 * 100.0 / self.flat_slots.max(1) as f64,
+        )
+    }
+}
@@ -3,6 +3,7 @@ use crate::client::Client;
 use crate::common::*;
 /// The event system for both client and server
 use crate::config::*;
+use crate::input::*;
 use crate::window::Window;
 use std::sync::Arc;
 use std::time::Duration;
@@ -19,20 +20,33 @@ pub struct Time {
 #[derive(Default)]
 pub struct FrameNum(pub usize);
 
-pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
+/// Runs the game until the window is closed or the server disconnects us, and then calls `on_exit`
+pub fn run_client_loop(
+    conn: Connection,
+    config: Arc<ClientConfig>,
+    spawn: Vector3<f32>,
+    on_exit: impl FnOnce() + 'static,
+) -> ! {
     let (window, evloop) = Window::new("Quanta");
 
     let mut w = World::new();
 
     let mut e: EventChannel<Event> = EventChannel::new();
 
-    let cam = Camera::new(window.size());
-    let (client, client_world) = Client::new(&window, &cam, conn, config, &mut e);
+    let mut cam = Camera::new(window.size());
+    cam.pos = Point3::from(spawn);
+    let (client, client_world) = Client::new(&window, &cam, conn, Arc::clone(&config), &mut e);
+
+    // Systems can write `Event::Quit` too, like when the server disconnects us, and then we stop
+    let mut quit_reader = e.register_reader();
 
     w.insert(e);
     w.insert(cam);
     w.insert(window);
-    w.insert(crate::world::World::new());
+    w.insert(crate::world::World::<SharedChunk>::new());
+    w.insert(crate::dag::MemoryStats::default());
+    w.insert(crate::client_world::RemotePlayers::default());
+    w.insert(crate::client_world::DayTime::default());
 
     let mut d = DispatcherBuilder::new()
         .with(client, "", &[])
@@ -42,6 +56,11 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
     let timer = stopwatch::Stopwatch::start_new();
     let mut i = 0;
     let mut time = Duration::from_secs(0);
+    let mut mods = Modifiers::default();
+    let mut on_exit = Some(on_exit);
+    // What the player's typing into chat, if they're typing.
+    // Enter starts and sends a message, or '/' starts a command, and Escape cancels it.
+    let mut chat: Option<String> = None;
 
     evloop.run(move |event, _target, _flow| {
         let mut e: specs::shred::FetchMut<EventChannel<Event>> = w.fetch_mut();
@@ -60,18 +79,69 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
                 e.single_write(Event::Quit);
                 *_flow = ControlFlow::Exit;
             }
+            we::Event::WindowEvent {
+                event: WindowEvent::ReceivedCharacter(c),
+                ..
+            } => match (&mut chat, c) {
+                (None, '\r') | (None, '\n') => {
+                    println!("Type a message and press Enter, or Escape to cancel");
+                    chat = Some(String::new());
+                }
+                (None, '/') => {
+                    println!("Type a command and press Enter, or Escape to cancel");
+                    chat = Some("/".to_string());
+                }
+                (None, _) => (),
+                (Some(text), '\r') | (Some(text), '\n') => {
+                    if !text.is_empty() {
+                        e.single_write(Event::Chat(std::mem::take(text)));
+                    }
+                    chat = None;
+                }
+                // Escape
+                (Some(_), '\u{1b}') => chat = None,
+                // Backspace
+                (Some(text), '\u{8}') => {
+                    text.pop();
+                }
+                (Some(text), c) if !c.is_control() => text.push(c),
+                (Some(_), _) => (),
+            },
+            we::Event::WindowEvent {
+                event: WindowEvent::ModifiersChanged(state),
+                ..
+            } => {
+                mods = Modifiers {
+                    shift: state.shift(),
+                    ctrl: state.ctrl(),
+                    alt: state.alt(),
+                };
+            }
             we::Event::DeviceEvent { event, .. } => {
                 // println!("Device event_a: {:?}", event);
                 match event {
                     DeviceEvent::MouseMotion { delta } => {
                         e.single_write(Event::Mouse(delta.0, delta.1));
                     }
+                    DeviceEvent::MouseWheel { delta } => {
+                        let lines = match delta {
+                            we::MouseScrollDelta::LineDelta(_, y) => y,
+                            we::MouseScrollDelta::PixelDelta(p) => p.y.signum() as f32,
+                        };
+                        e.single_write(Event::Scroll(lines));
+                    }
                     DeviceEvent::Key(we::KeyboardInput {
                         scancode,
                         state: we::ElementState::Pressed,
                         ..
                     }) => {
                         e.single_write(Event::KeyPressed(scancode));
+                        // Typing in chat shouldn't move us around
+                        if chat.is_none() {
+                            for action in config.keycodes.actions(Input::Key(scancode), mods) {
+                                e.single_write(Event::Action(action, true));
+                            }
+                        }
                     }
                     DeviceEvent::Key(we::KeyboardInput {
                         scancode,
@@ -79,16 +149,43 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
                         ..
                     }) => {
                         e.single_write(Event::KeyReleased(scancode));
+                        // Releasing a key should always stop the action, even if the modifiers changed since
+                        for action in config.keycodes.actions(
+                            Input::Key(scancode),
+                            Modifiers {
+                                shift: true,
+                                ctrl: true,
+                                alt: true,
+                            },
+                        ) {
+                            e.single_write(Event::Action(action, false));
+                        }
                     }
                     DeviceEvent::Button {
                         state: we::ElementState::Pressed,
                         button,
                     } => {
                         e.single_write(Event::Button(button));
+                        for action in config.keycodes.actions(Input::Mouse(button), mods) {
+                            e.single_write(Event::Action(action, true));
+                        }
+                    }
+                    DeviceEvent::Button {
+                        state: we::ElementState::Released,
+                        button,
+                    } => {
+                        for action in config.keycodes.actions(Input::Mouse(button), mods) {
+                            e.single_write(Event::Action(action, false));
+                        }
                     }
                     _ => {}
                 }
             }
+            we::Event::LoopDestroyed => {
+                if let Some(f) = on_exit.take() {
+                    f();
+                }
+            }
             we::Event::RedrawEventsCleared => {
                 drop(e);
 
@@ -102,6 +199,11 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
                 d.dispatch_par(&w);
                 w.maintain();
 
+                let e: specs::shred::Fetch<EventChannel<Event>> = w.fetch();
+                if e.read(&mut quit_reader).any(|x| matches!(x, Event::Quit)) {
+                    *_flow = ControlFlow::Exit;
+                }
+
                 // Keep the cursor in the window
                 // window.surface
                 //     .window()
@@ -122,18 +224,27 @@ pub fn run_client_loop(conn: Connection, config: Arc<ClientConfig>) -> ! {
 }
 
 pub enum Event {
-    /// The player moved
-    PlayerMove(Vector3<f32>),
+    /// The player moved or looked around: (position, direction)
+    PlayerMove(Vector3<f32>, Vector3<f32>),
     Submit(
         Once<(
             vulkano::command_buffer::AutoCommandBuffer,
             Vector3<f32>,
             f32,
-            HashMap<Vector3<i32>, (usize, usize)>,
         )>,
     ),
+    /// The player wants to set the full-size block with its low corner here, which goes through the server
+    SetBlock(Vector3<i32>, Block),
+    /// The player typed this into chat
+    Chat(String),
+    /// The server moved the player here
+    Teleport(Vector3<f32>),
+    /// The mouse wheel moved this many lines, positive is up
+    Scroll(f32),
     /// A press of a mouse button with this id
     Button(u32),
+    /// An action was started (true) or stopped (false) with whatever it's bound to in the config
+    Action(Action, bool),
     /// A key press with this scan code
     KeyPressed(u32),
     KeyReleased(u32),
@@ -1,22 +1,171 @@
 use serde::{Deserialize, Serialize};
 
+/// Something the player can do, which is bound to a key or mouse button in `KeyCodes`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    Forward,
+    Left,
+    Back,
+    Right,
+    /// Jump, or fly or swim up
+    Up,
+    /// Fly or swim down
+    Down,
+    /// Switch between flying and walking
+    ToggleFly,
+    /// Break the block we're looking at
+    Break,
+    /// Place a block on the face we're looking at
+    Place,
+    Screenshot,
+    /// Select the next or previous material in the palette
+    NextMaterial,
+    PrevMaterial,
+    /// Select this material in the palette
+    SelectMaterial(usize),
+}
+
+/// The modifier keys that are held down
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Modifiers {
+    /// Whether all the modifiers in `required` are held
+    pub fn contains(self, required: Modifiers) -> bool {
+        (self.shift || !required.shift)
+            && (self.ctrl || !required.ctrl)
+            && (self.alt || !required.alt)
+    }
+}
+
+/// A key or mouse button that triggers an action.
+/// In `config.ron`, a plain number is a key scancode, so `forward: 17` still works.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Binding {
+    /// A key, by scancode
+    Key(u32),
+    /// A key that only counts while some modifiers are held, like `(key: 31, mods: (ctrl: true))`
+    KeyWith { key: u32, mods: Modifiers },
+    /// A mouse button, like `(mouse: 1)`
+    Mouse { mouse: u32 },
+}
+
+/// An input from the player, which `KeyCodes` turns into actions
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Input {
+    /// A key, by scancode
+    Key(u32),
+    /// A mouse button, by id
+    Mouse(u32),
+}
+
+impl Binding {
+    pub fn matches(self, input: Input, mods: Modifiers) -> bool {
+        match (self, input) {
+            (Binding::Key(k), Input::Key(i)) => k == i,
+            (
+                Binding::KeyWith {
+                    key,
+                    mods: required,
+                },
+                Input::Key(i),
+            ) => key == i && mods.contains(required),
+            (Binding::Mouse { mouse }, Input::Mouse(i)) => mouse == i,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct KeyCodes {
-    pub forward: u32,
-    pub left: u32,
-    pub back: u32,
-    pub right: u32,
+    pub forward: Binding,
+    pub left: Binding,
+    pub back: Binding,
+    pub right: Binding,
+
+    pub up: Binding,
+    pub down: Binding,
 
-    pub up: u32,
-    pub down: u32,
+    pub toggle_fly: Binding,
+    pub break_block: Binding,
+    pub place_block: Binding,
+    pub screenshot: Binding,
+    pub next_material: Binding,
+    pub prev_material: Binding,
+    /// Select the first nine materials in the palette
+    pub materials: [Binding; 9],
+}
+
+impl KeyCodes {
+    pub fn bindings(&self) -> Vec<(Binding, Action)> {
+        let mut bindings = vec![
+            (self.forward, Action::Forward),
+            (self.left, Action::Left),
+            (self.back, Action::Back),
+            (self.right, Action::Right),
+            (self.up, Action::Up),
+            (self.down, Action::Down),
+            (self.toggle_fly, Action::ToggleFly),
+            (self.break_block, Action::Break),
+            (self.place_block, Action::Place),
+            (self.screenshot, Action::Screenshot),
+            (self.next_material, Action::NextMaterial),
+            (self.prev_material, Action::PrevMaterial),
+        ];
+        for (i, &b) in self.materials.iter().enumerate() {
+            bindings.push((b, Action::SelectMaterial(i)));
+        }
+        bindings
+    }
+
+    /// The actions bound to `input` with the modifiers `mods` held
+    pub fn actions(&self, input: Input, mods: Modifiers) -> Vec<Action> {
+        self.bindings()
+            .into_iter()
+            .filter(|(binding, _)| binding.matches(input, mods))
+            .map(|(_, action)| action)
+            .collect()
+    }
+}
+
+impl Default for KeyCodes {
+    fn default() -> Self {
+        DEFAULT_KEY_CODES
+    }
 }
 
 pub const DEFAULT_KEY_CODES: KeyCodes = KeyCodes {
-    forward: 17, // W
-    left: 30,    // A
-    back: 31,    // S
-    right: 32,   // D
+    forward: Binding::Key(17), // W
+    left: Binding::Key(30),    // A
+    back: Binding::Key(31),    // S
+    right: Binding::Key(32),   // D
+
+    up: Binding::Key(57),   // Space
+    down: Binding::Key(42), // LShift
 
-    up: 56,   // Space
-    down: 42, // LShift
+    toggle_fly: Binding::Key(33),             // F
+    break_block: Binding::Mouse { mouse: 1 }, // Left click
+    place_block: Binding::Mouse { mouse: 3 }, // Right click
+    screenshot: Binding::Key(60),             // F2
+    next_material: Binding::Key(18),          // E
+    prev_material: Binding::Key(16),          // Q
+    // 1 through 9
+    materials: [
+        Binding::Key(2),
+        Binding::Key(3),
+        Binding::Key(4),
+        Binding::Key(5),
+        Binding::Key(6),
+        Binding::Key(7),
+        Binding::Key(8),
+        Binding::Key(9),
+        Binding::Key(10),
+    ],
 };
@@ -0,0 +1,35 @@
+//! The game, shared between the client (`quanta`) and the dedicated server (`quanta-server`).
+//! Everything that needs a window or a GPU is behind the `client` feature.
+
+pub mod block;
+pub mod chunk_thread;
+pub mod command;
+pub mod common;
+pub mod config;
+pub mod input;
+pub mod material;
+pub mod octree;
+pub mod server;
+pub mod shape;
+pub mod terrain;
+pub mod world;
+
+#[cfg(feature = "client")]
+pub mod camera;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod client_world;
+#[cfg(feature = "client")]
+pub mod dag;
+#[cfg(feature = "client")]
+pub mod event;
+#[cfg(feature = "client")]
+pub mod shaders;
+#[cfg(feature = "client")]
+pub mod window;
+
+pub const APP_INFO: app_dirs2::AppInfo = app_dirs2::AppInfo {
+    name: "quanta",
+    author: "Lorxu",
+};
@@ -35,6 +35,38 @@ layout(set=0, binding=2, std430) buffer material_buffer {
   MatData mats[];
 };
 
+// Simple coloured boxes drawn on top of the world, like other players.
+// An entity with lo > hi is never hit, so the buffer is never empty.
+struct Entity {
+  vec4 lo;
+  vec4 hi;
+  vec4 color;
+};
+layout(set=1, binding=0, std430) buffer entity_buffer {
+  Entity entities[];
+};
+
+// Returns the distance to the closest entity along the ray, or -1.0 if there isn't one
+float trace_entities(in vec3 ro, in vec3 rd, out int hit, out vec3 normal) {
+  vec3 rdi = 1.0 / rd;
+  float best = -1.0;
+  hit = -1;
+  for (int j = 0; j < entities.length(); j++) {
+    vec3 t1 = (entities[j].lo.xyz - ro) * rdi;
+    vec3 t2 = (entities[j].hi.xyz - ro) * rdi;
+    vec3 tmin = min(t1, t2);
+    vec3 tmax = max(t1, t2);
+    float near = max(max(tmin.x, tmin.y), tmin.z);
+    float far = min(min(tmax.x, tmax.y), tmax.z);
+    if (near <= far && near > 0.0 && (best < 0.0 || near < best)) {
+      best = near;
+      hit = j;
+      normal = -sign(rd) * step(tmin.yzx, tmin) * step(tmin.zxy, tmin);
+    }
+  }
+  return best;
+}
+
 void main() {
   vec2 uv = frag_coord_ndc;
   vec4 ts = textureGather(beam_image, uv*0.5+0.5);
@@ -64,8 +96,16 @@ void main() {
   int i = 256;
   vec3 p;
   uint result = trace(ro, rd, t, i, p);
-  if (result != 0) {
-    MatData mat = mats[result];
+
+  // Entities aren't in the beam image, so they're traced from the camera
+  int e;
+  vec3 n;
+  float et = trace_entities(camera_pos, rd, e, n);
+  if (e >= 0 && (result == 0 || et < start_t + t.x)) {
+    vec3 col = entities[e].color.rgb * (0.3 + 0.7 * saturate(dot(n, sun_dir)) * smoothstep(0.0, 0.1, sun_dir.y));
+    frag_color = vec4(applyFog(col, et, camera_pos, rd, sun_dir), 1.0);
+  } else if (result != 0) {
+    MatData mat = mats[result & 0xFFu];
     //mat.color = vec3(0.3, 0.6, 0.1);
     frag_color = vec4(shade(ro, rd, t, p, mat), 1.0);
   } else {
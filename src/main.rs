@@ -1,61 +1,124 @@
-use crate::config::*;
+use quanta::common::*;
+use quanta::config::*;
+use quanta::{client_world, event, server, APP_INFO};
 use std::fs::File;
 use std::io::Write;
 
-use std::sync::Arc;
-
-mod camera;
-mod chunk_thread;
-mod client;
-mod client_world;
-mod common;
-mod config;
-mod event;
-mod input;
-mod material;
-mod octree;
-mod server;
-mod shaders;
-mod terrain;
-mod window;
-mod world;
-use common::*;
-
-pub const APP_INFO: app_dirs2::AppInfo = app_dirs2::AppInfo {
-    name: "quanta",
-    author: "Lorxu",
-};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// The server we run ourselves if we aren't connecting to another one.
+/// It's stopped when the window closes or the server disconnects us, on Ctrl-C, or when this is dropped while unwinding from a panic, so it always saves the world.
+/// Nothing on the client should call `std::process::exit`, since that skips all of these.
+struct LocalServer(Mutex<Option<(server::StopHandle, JoinHandle<()>)>>);
+
+impl LocalServer {
+    /// Stops the server and waits for it to save everything
+    fn stop(&self) {
+        // If another thread panicked while holding the lock, we still want to save
+        let server = self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some((stop, thread)) = server {
+            stop.stop();
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
 
 fn main() {
     let mut config_file =
         app_dirs2::app_root(app_dirs2::AppDataType::UserConfig, &APP_INFO).unwrap();
     config_file.push("config.ron");
-    let client_config = if config_file.exists() {
+    let mut client_config = if config_file.exists() {
         ron::de::from_reader(File::open(config_file).unwrap()).expect("bad config file")
     } else {
         let c = ClientConfig {
-            keycodes: crate::input::DEFAULT_KEY_CODES,
-            game_config: Arc::new(GameConfig {
-                draw_chunks: 16,
-                batch_size: 64,
-                save_chunks: true,
-            }),
+            keycodes: quanta::input::DEFAULT_KEY_CODES,
+            dag: false,
+            name: "Player".to_string(),
+            game_config: Arc::new(GameConfig::default()),
         };
         let s = ron::ser::to_string(&c).unwrap();
         let mut f = File::create(config_file).unwrap();
         writeln!(f, "{}", s).unwrap();
         c
     };
-    let client_config = Arc::new(client_config);
-
     let config = Arc::clone(&client_config.game_config);
 
-    let (conn_client, conn_server) = Connection::local();
-    std::thread::spawn(move || {
-        let mut server = server::Server::new(config);
-        server.join(conn_server, Vector3::zeros());
-        server.run();
-    });
+    // `--connect <address>` joins a server in another process.
+    // Otherwise we run our own, and `--listen <address>` lets other players join it.
+    let args: Vec<String> = std::env::args().collect();
+    let arg = |name: &str| {
+        args.iter().position(|x| x == name).map(|i| {
+            args.get(i + 1)
+                .unwrap_or_else(|| panic!("{} needs an address", name))
+        })
+    };
+
+    let (conn_client, local_server) = match arg("--connect") {
+        Some(addr) => {
+            let stream = std::net::TcpStream::connect(addr)
+                .unwrap_or_else(|e| panic!("Couldn't connect to {}: {}", addr, e));
+            println!("Connected to {}", addr);
+            (Connection::tcp(stream).unwrap(), None)
+        }
+        None => {
+            let listen = arg("--listen").cloned();
+            let (conn_client, conn_server) = Connection::local();
+            let (to, from) = std::sync::mpsc::channel();
+            let thread = std::thread::spawn(move || {
+                // Single player worlds go in the app's data directory
+                let world_dir =
+                    app_dirs2::app_root(app_dirs2::AppDataType::UserData, &APP_INFO).unwrap();
+                let mut server = server::Server::new(config, world_dir);
+                if let Some(addr) = listen {
+                    server
+                        .listen(&addr)
+                        .unwrap_or_else(|e| panic!("Couldn't listen on {}: {}", addr, e));
+                }
+                to.send(server.stop_handle()).unwrap();
+                server.join(conn_server);
+                server.run();
+            });
+            let stop = from.recv().expect("Server didn't start");
+            (conn_client, Some((stop, thread)))
+        }
+    };
+
+    let local_server = Arc::new(LocalServer(Mutex::new(local_server)));
+    // This is weak so the server is still stopped when everything else lets go of it
+    let l = Arc::downgrade(&local_server);
+    ctrlc::set_handler(move || {
+        if let Some(l) = l.upgrade() {
+            l.stop();
+        }
+        std::process::exit(0);
+    })
+    .expect("Couldn't set a Ctrl-C handler");
 
-    event::run_client_loop(conn_client, client_config);
+    let spawn = match client_world::handshake(&conn_client, &client_config) {
+        Ok((_id, spawn, draw_chunks)) => {
+            // The server might not send chunks as far away as we wanted, so we shouldn't keep them that long either
+            let mut game_config = GameConfig::clone(&client_config.game_config);
+            game_config.draw_chunks = draw_chunks;
+            client_config.game_config = Arc::new(game_config);
+            spawn
+        }
+        Err(e) => {
+            println!("Couldn't join the server: {}", e);
+            return;
+        }
+    };
+    let client_config = Arc::new(client_config);
+
+    let l = Arc::clone(&local_server);
+    event::run_client_loop(conn_client, client_config, spawn, move || {
+        // Our own server stops when we quit or get kicked, once it's saved the world
+        l.stop();
+    });
 }
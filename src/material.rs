@@ -1,3 +1,4 @@
+#[cfg(feature = "client")]
 use crate::shaders::MatData;
 use enum_iterator::IntoEnumIterator;
 use serde::{Deserialize, Serialize};
@@ -26,10 +27,24 @@ pub enum Material {
 }
 
 impl Material {
+    /// Whether things collide with this material. You can walk through air and swim through water.
+    pub fn is_solid(self) -> bool {
+        !matches!(self, Material::Air | Material::Water)
+    }
+
+    /// The id and name of every material, which the server sends so clients can check they match
+    pub fn table() -> Vec<(u16, String)> {
+        Material::into_enum_iter()
+            .map(|x| (x as u16, format!("{:?}", x)))
+            .collect()
+    }
+
+    #[cfg(feature = "client")]
     pub fn all() -> Vec<MatData> {
         Material::into_enum_iter().map(|x| x.mat_data()).collect()
     }
 
+    #[cfg(feature = "client")]
     pub fn mat_data(self) -> MatData {
         match self {
             Material::Stone => MatData {
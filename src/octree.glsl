@@ -17,7 +17,8 @@ vec3 v_idx(bvec3 b) {
 }
 
 #ifndef STACKLESS
-const int MAX_LEVELS = 8;
+// The stack holds the path through the root structure as well as the chunk, so this is MAX_ROOT_LEVELS + MAX_LEVELS from octree.rs
+const int MAX_LEVELS = 16;
 
 struct ST {
     uint parent_pointer;
@@ -1,9 +1,71 @@
 use crate::common::*;
-use num_traits::FromPrimitive;
+use std::sync::Arc;
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Chunk(pub Vec<u32>);
 
+/// The deepest a chunk's octree can go, counting the root node as level 1.
+pub const MAX_LEVELS: u32 = 8;
+/// The most levels `ClientWorld.root` can have above the chunks, which is enough for a root 256 chunks across.
+/// The shader traverses the root and a chunk with the same stack, so `MAX_LEVELS` in `octree.glsl` must be `MAX_ROOT_LEVELS + MAX_LEVELS`.
+pub const MAX_ROOT_LEVELS: u32 = 8;
+
+/// The level of full-size blocks: a chunk's root node is level 1, and each level halves the size.
+pub const BLOCK_LEVEL: u32 = 4;
+/// The finest level blocks can be placed at. Levels past `BLOCK_LEVEL` are micro-blocks, so this allows quarter-size blocks.
+/// It must be no more than `MAX_LEVELS`, or chunks with the smallest micro-blocks won't validate.
+pub const MAX_BLOCK_LEVEL: u32 = BLOCK_LEVEL + 2;
+
+/// A problem with a chunk's octree, found by `Chunk::validate()`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChunkError {
+    /// The chunk doesn't have room for a root node
+    TooShort(usize),
+    /// The pointer in `slot` leads to a node that doesn't fit in the chunk
+    OutOfBounds { slot: usize, target: usize },
+    /// The pointer in `slot` leads back to its own node
+    Cycle { slot: usize },
+    /// The pointer in `slot` leads to a node deeper than `MAX_LEVELS`
+    TooDeep { slot: usize },
+    /// The leaf in `slot` has a material id that doesn't exist, or is Air with a state.
+    /// `id` is the leaf's material and state bits.
+    BadMaterial { slot: usize, id: u32 },
+}
+
+impl std::fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChunkError::TooShort(len) => write!(f, "chunk has {} slots, less than a node", len),
+            ChunkError::OutOfBounds { slot, target } => write!(
+                f,
+                "slot {} points to a node at {}, past the end of the chunk",
+                slot, target
+            ),
+            ChunkError::Cycle { slot } => write!(f, "slot {} points to its own node", slot),
+            ChunkError::TooDeep { slot } => write!(
+                f,
+                "slot {} points to a node deeper than {} levels",
+                slot, MAX_LEVELS
+            ),
+            ChunkError::BadMaterial { slot, id } => {
+                write!(f, "slot {} has unknown block id {}", slot, id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+/// The number of blocks along one side of a chunk
+pub const CHUNK_BLOCKS: usize = CHUNK_SIZE as usize;
+/// The number of blocks in a chunk, the length of the arrays used by `Chunk::from_dense()` and `Chunk::to_dense()`
+pub const CHUNK_VOLUME: usize = CHUNK_BLOCKS * CHUNK_BLOCKS * CHUNK_BLOCKS;
+
+/// The index in a dense array of the block at `p`, counting from the low corner of the chunk
+pub fn dense_idx(p: Vector3<usize>) -> usize {
+    p.x + p.y * CHUNK_BLOCKS + p.z * CHUNK_BLOCKS * CHUNK_BLOCKS
+}
+
 use std::ops::{Deref, DerefMut};
 impl Deref for Chunk {
     type Target = Vec<u32>;
@@ -22,9 +84,80 @@ impl DerefMut for Chunk {
 pub struct RayCast {
     pub t: [f32; 2],
     pub mat: Material,
+    pub state: BlockState,
     /// The center of the block we hit, relative to the chunk center.
     /// Note that `ro+rd*t` is the hit position.
     pub pos: Vector3<f32>,
+    /// The side length of the leaf node we hit, which is less than 1 for micro-blocks
+    pub size: f32,
+    /// The normal of the face we hit, pointing back towards the ray. It's zero if the ray started inside the block.
+    pub normal: Vector3<f32>,
+    /// The low corner of the full-size block we hit, which is inside the leaf node even if the leaf is bigger than a block.
+    /// For micro-blocks, it's the full-size block containing the micro-block.
+    pub voxel: Vector3<i32>,
+    /// The low corner of the full-size block in front of the face we hit, which is where a block placed on that face would go.
+    /// For micro-blocks this can be the same as `voxel`.
+    pub adjacent: Vector3<i32>,
+}
+
+/// A subtree cut out of a chunk by `Chunk::split()`, as (slot that pointed to it, start of that slot's node, subtree)
+pub type Subtree = (usize, usize, Vec<u32>);
+
+/// A non-empty leaf node, as returned by `Chunk::leaves()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Leaf {
+    /// The corner with the lowest coordinates, relative to the chunk center
+    pub min: Vector3<f32>,
+    pub size: f32,
+    pub mat: Material,
+    pub state: BlockState,
+}
+
+/// Iterator over the non-empty leaves of a chunk, in depth-first order
+pub struct Leaves<'a, T = Chunk> {
+    chunk: &'a T,
+    bounds: Option<Aabb>,
+    /// The slots we still need to visit, as (slot, start of its node, center, size)
+    stack: Vec<(usize, usize, Vector3<f32>, f32)>,
+}
+
+impl<'a, T> Leaves<'a, T> {
+    /// Queues up the children of the node starting at `parent`, which has center `pos` and side length `size`
+    fn push_node(&mut self, parent: usize, pos: Vector3<f32>, size: f32) {
+        let size = size * 0.5;
+        // Reversed so we visit them in order
+        for uidx in (0..8).rev() {
+            let pos = pos + idx_to_pos(uidx) * size * 0.5;
+            if let Some(bounds) = &self.bounds {
+                if !bounds.intersects(&Aabb::cube(pos, size)) {
+                    continue;
+                }
+            }
+            self.stack.push((parent + uidx, parent, pos, size));
+        }
+    }
+}
+
+impl<'a, T: Octree> Iterator for Leaves<'a, T> {
+    type Item = Leaf;
+
+    fn next(&mut self) -> Option<Leaf> {
+        while let Some((ptr, parent, pos, size)) = self.stack.pop() {
+            let node = self.chunk.node(ptr);
+            if node & 1 > 0 {
+                self.push_node(parent + (node >> 1) as usize, pos, size);
+            } else if node != 0 {
+                let block = Block::from_leaf(node);
+                return Some(Leaf {
+                    min: pos.map(|x| x - size * 0.5),
+                    size,
+                    mat: block.mat,
+                    state: block.state,
+                });
+            }
+        }
+        None
+    }
 }
 
 /// Returns (t, tmid, tmax)
@@ -46,10 +179,30 @@ pub fn isect(
     ([tmin.max(), tmax.min()], tmid, tmax)
 }
 
-impl Chunk {
+/// Anything that can be read like a chunk's octree: a `Chunk`, or a `SharedChunk` that shares some of its subtrees.
+/// All the lookups and traversals are here, and edits go through `make_mut()`.
+pub trait Octree: Sized {
+    /// The node in slot `i`, which is `chunk[i]` for a `Chunk`
+    fn node(&self, i: usize) -> u32;
+
+    /// The `Chunk` to edit, which is itself for a `Chunk`.
+    /// A `SharedChunk` has to copy its shared subtrees into a `Chunk` first, like `Arc::make_mut()`.
+    fn make_mut(&mut self) -> &mut Chunk;
+
     /// Casts a ray from a position relative to the chunk center
+    fn raycast(&self, ro: Vector3<f32>, rd: Vector3<f32>, max_iters: usize) -> Option<RayCast> {
+        self.raycast_filter(ro, rd, max_iters, |_| true)
+    }
+
+    /// Casts a ray from a position relative to the chunk center, going through blocks where `filter` returns false
     #[allow(clippy::float_cmp)]
-    pub fn raycast(&self, ro: Vector3<f32>, rd: Vector3<f32>, max_iters: usize) -> Option<RayCast> {
+    fn raycast_filter(
+        &self,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+        max_iters: usize,
+        filter: impl Fn(Material) -> bool,
+    ) -> Option<RayCast> {
         struct ST {
             parent: usize,
             pos: Vector3<f32>,
@@ -90,7 +243,7 @@ impl Chunk {
 
             let uidx = pos_to_idx(idx);
 
-            let node = self[parent + uidx];
+            let node = self.node(parent + uidx);
 
             if (node & 1) > 0 {
                 // Non-leaf
@@ -118,12 +271,37 @@ impl Chunk {
                     pos += 0.5 * size * idx;
                     continue;
                 }
-            } else if node != 0 {
+            } else if node != 0 && filter(Block::from_leaf(node).mat) {
                 // Nonempty, but leaf
+                let block = Block::from_leaf(node);
+
+                // We came in through the side we reached last
+                let normal = if t[0] > 0.0 {
+                    let tmin = tmid - rdi.abs() * size * 0.5;
+                    let axis = tmin.imax();
+                    let mut normal = Vector3::zeros();
+                    normal[axis] = -tstep[axis];
+                    normal
+                } else {
+                    Vector3::zeros()
+                };
+
+                // A point just inside the face we hit, within the leaf and within one block (or micro-block) of the face
+                let s = size.min(1.0);
+                let hit = ro + rd * t[0].max(0.0) - normal * s * 0.5;
+                let hit = hit.zip_map(&pos, |x, p| {
+                    na::clamp(x, p - size * 0.5 + s * 0.25, p + size * 0.5 - s * 0.25)
+                });
+
                 return Some(RayCast {
-                    mat: Material::from_u32(node >> 1).unwrap_or(Material::Wrong),
+                    mat: block.mat,
+                    state: block.state,
                     t,
                     pos,
+                    size,
+                    normal,
+                    voxel: hit.map(|x| x.floor() as i32),
+                    adjacent: (hit + normal * s).map(|x| x.floor() as i32),
                 });
             }
 
@@ -150,12 +328,40 @@ impl Chunk {
             }
         }
 
-        println!("WARNING: ran out of iterations in Chunk::raycast()!");
+        println!("WARNING: ran out of iterations in Octree::raycast()!");
         None
     }
 
+    /// Iterates over every non-empty leaf in the chunk
+    fn leaves(&self) -> Leaves<'_, Self> {
+        let mut leaves = Leaves {
+            chunk: self,
+            bounds: None,
+            stack: Vec::new(),
+        };
+        leaves.push_node(0, Vector3::zeros(), CHUNK_SIZE);
+        leaves
+    }
+
+    /// Iterates over the non-empty leaves that intersect `bounds`, which is relative to the chunk center.
+    /// Nodes outside of `bounds` are skipped without visiting their children.
+    fn leaves_in(&self, bounds: Aabb) -> Leaves<'_, Self> {
+        let mut leaves = Leaves {
+            chunk: self,
+            bounds: Some(bounds),
+            stack: Vec::new(),
+        };
+        leaves.push_node(0, Vector3::zeros(), CHUNK_SIZE);
+        leaves
+    }
+
     /// Get the material at a location relative to the chunk center
-    pub fn block(&self, target: Vector3<f32>) -> Material {
+    fn block(&self, target: Vector3<f32>) -> Material {
+        self.get_block(target).mat
+    }
+
+    /// Get the material and state at a location relative to the chunk center
+    fn get_block(&self, target: Vector3<f32>) -> Block {
         let mut size = CHUNK_SIZE;
         let mut pos = Vector3::zeros();
         let mut parent = 0;
@@ -166,24 +372,131 @@ impl Chunk {
             pos += idx * size * 0.5;
 
             let uidx = pos_to_idx(idx);
-            let node = self[parent + uidx];
+            let node = self.node(parent + uidx);
 
             // We have more nodes to traverse within this one
             if node & 1 > 0 {
                 parent += (node >> 1) as usize;
             } else {
-                break Material::from_u32(node >> 1).unwrap();
+                break Block::from_leaf(node);
             }
         }
     }
+}
+
+impl Octree for Chunk {
+    fn node(&self, i: usize) -> u32 {
+        self[i]
+    }
+
+    fn make_mut(&mut self) -> &mut Chunk {
+        self
+    }
+}
 
-    /// Set the material at a location relative to the chunk center
-    /// Setting a block to air does work, but uses more space
-    pub fn set_block(&mut self, target: Vector3<f32>, level: u32, new: Material) {
+/// A chunk split by `Chunk::split()`, which keeps its subtrees in `Arc`s so other chunks with the same subtrees can share them.
+/// It reads like the flat chunk you'd get by appending every subtree to the top part in order.
+#[derive(Clone, Debug)]
+pub struct SharedChunk {
+    top: Chunk,
+    /// Each subtree, and where it would start if they were all appended to `top`
+    subtrees: Vec<(usize, Arc<[u32]>)>,
+}
+
+impl SharedChunk {
+    /// Puts a chunk back together from the top part returned by `Chunk::split()`, and a shared copy of each of its subtrees,
+    /// as (slot that points to it, start of that slot's node, subtree)
+    pub fn new(
+        mut top: Vec<u32>,
+        subtrees: impl IntoIterator<Item = (usize, usize, Arc<[u32]>)>,
+    ) -> Self {
+        let mut start = top.len();
+        let mut shared = Vec::new();
+        for (slot, parent, subtree) in subtrees {
+            top[slot] = ((start - parent) << 1 | 1) as u32;
+            let len = subtree.len();
+            shared.push((start, subtree));
+            start += len;
+        }
+        SharedChunk {
+            top: Chunk(top),
+            subtrees: shared,
+        }
+    }
+
+    /// The number of slots it would take up as a `Chunk`
+    pub fn len(&self) -> usize {
+        self.subtrees
+            .last()
+            .map_or(self.top.len(), |(start, subtree)| start + subtree.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies it into a `Chunk`, leaving this one as it is
+    pub fn to_chunk(&self) -> Chunk {
+        let mut chunk = self.top.clone();
+        for (_, subtree) in &self.subtrees {
+            chunk.extend(subtree.iter());
+        }
+        chunk
+    }
+}
+
+/// A chunk that doesn't share anything
+impl From<Chunk> for SharedChunk {
+    fn from(chunk: Chunk) -> Self {
+        SharedChunk {
+            top: chunk,
+            subtrees: Vec::new(),
+        }
+    }
+}
+
+impl Octree for SharedChunk {
+    fn node(&self, i: usize) -> u32 {
+        if i < self.top.len() {
+            return self.top[i];
+        }
+        let k = match self.subtrees.binary_search_by_key(&i, |(start, _)| *start) {
+            Ok(k) => k,
+            Err(k) => k - 1,
+        };
+        let (start, subtree) = &self.subtrees[k];
+        subtree[i - start]
+    }
+
+    /// Appends the subtrees to the top part, which is where the pointers to them already lead, and stops sharing them
+    fn make_mut(&mut self) -> &mut Chunk {
+        for (_, subtree) in self.subtrees.drain(..) {
+            self.top.extend(subtree.iter());
+        }
+        &mut self.top
+    }
+}
+
+impl Chunk {
+    /// Set the block at a location relative to the chunk center, which can be a `Material` or a `Block` with a state.
+    /// `level` is the size of the block to set: `BLOCK_LEVEL` for full-size blocks, or up to `MAX_BLOCK_LEVEL` for micro-blocks.
+    /// Nodes left with eight identical leaves are merged back into their parent,
+    /// and the chunk is compacted if that leaves unreachable nodes behind.
+    pub fn set_block(&mut self, target: Vector3<f32>, level: u32, new: impl Into<Block>) {
+        assert!(
+            level <= MAX_BLOCK_LEVEL,
+            "Blocks can't be smaller than level {}",
+            MAX_BLOCK_LEVEL
+        );
+        let new = new.into().leaf();
         let mut size = CHUNK_SIZE;
         let mut pos = Vector3::zeros();
         let mut parent = 0;
 
+        // The pointers we followed to get here, as (slot, node it points to)
+        let mut path = Vec::new();
+        let mut orphaned = false;
+
         // Find the spot to put this block, creating a new subtree if necessary
         for i in 0..level {
             size *= 0.5;
@@ -192,31 +505,316 @@ impl Chunk {
 
             let uidx = pos_to_idx(idx);
             let ptr = parent + uidx;
+            let node = self[ptr];
 
             if i == level - 1 {
-                // Actually put the new material there
-                self[ptr] = (new as u32) << 1;
+                // Actually put the new material there, replacing anything below it
+                orphaned = node & 1 > 0;
+                self[ptr] = new;
                 break;
             }
 
-            let node = self[ptr];
-
             // We have more nodes to traverse within this one
             if node & 1 > 0 {
                 parent += (node >> 1) as usize;
+            } else if node == new {
+                // It's already the right material, so there's nothing to do
+                return;
             } else {
                 // Create a new node
                 self[ptr] = ((self.len() - parent) as u32) << 1 | 1;
                 parent = self.len();
                 self.extend((0..8).map(|_| node));
             }
+            path.push((ptr, parent));
+        }
+
+        // Merge any nodes that are now all one material, from the bottom up
+        while let Some((ptr, child)) = path.pop() {
+            let leaf = self[child];
+            if leaf & 1 == 0 && self[child..child + 8].iter().all(|&x| x == leaf) {
+                self[ptr] = leaf;
+                orphaned = true;
+            } else {
+                break;
+            }
+        }
+
+        if orphaned {
+            self.compact();
+        }
+    }
+
+    /// Replaces every block inside `shape`, which is relative to the chunk center, with `f` of its current material.
+    /// Blocks that change material lose their state.
+    /// Nodes entirely inside the shape are rewritten as a whole, and the result is compacted.
+    /// Returns whether anything changed.
+    pub fn edit(&mut self, shape: &impl Shape, f: impl Fn(Material) -> Material) -> bool {
+        let new = self.edit_node(0, Vector3::zeros(), CHUNK_SIZE, shape, &f, false);
+        let changed = new != self.compact_node(0);
+        self.0 = new;
+        changed
+    }
+
+    /// Edits the node starting at `parent`, with center `pos` and side length `size`.
+    /// If `inside` is true, the whole node is inside the shape.
+    /// Returns the new node followed by all its descendants, compacted.
+    fn edit_node(
+        &self,
+        parent: usize,
+        pos: Vector3<f32>,
+        size: f32,
+        shape: &impl Shape,
+        f: &impl Fn(Material) -> Material,
+        inside: bool,
+    ) -> Vec<u32> {
+        let size = size * 0.5; // Child size
+        let mut ret = vec![0; 8];
+        for uidx in 0..8 {
+            let pos = pos + idx_to_pos(uidx) * size * 0.5;
+            let node = self[parent + uidx];
+
+            let coverage = if inside {
+                Coverage::Inside
+            } else if size <= 1.0 {
+                // Blocks are either in or out, depending on their center
+                if shape.contains(pos) {
+                    Coverage::Inside
+                } else {
+                    Coverage::Outside
+                }
+            } else {
+                shape.coverage(pos, size)
+            };
+
+            let child = if node & 1 > 0 {
+                let ptr = parent + (node >> 1) as usize;
+                match coverage {
+                    Coverage::Outside => self.compact_node(ptr),
+                    Coverage::Partial => self.edit_node(ptr, pos, size, shape, f, false),
+                    Coverage::Inside => self.edit_node(ptr, pos, size, shape, f, true),
+                }
+            } else {
+                match coverage {
+                    Coverage::Outside => {
+                        ret[uidx] = node;
+                        continue;
+                    }
+                    Coverage::Inside => {
+                        let old = Block::from_leaf(node);
+                        let mat = f(old.mat);
+                        ret[uidx] = if mat == old.mat {
+                            node
+                        } else {
+                            Block::from(mat).leaf()
+                        };
+                        continue;
+                    }
+                    // Split the leaf up so we can edit part of it
+                    Coverage::Partial => {
+                        Chunk(vec![node; 8]).edit_node(0, pos, size, shape, f, false)
+                    }
+                }
+            };
+            push_child(&mut ret, uidx, child);
+        }
+        ret
+    }
+
+    /// Splits the chunk into the nodes above `level`, and the compacted subtrees starting at `level`, so the subtrees can be stored somewhere else.
+    /// Returns the top part, and the subtrees that were cut out of it; their slots are left as 0.
+    pub fn split(&self, level: u32) -> (Vec<u32>, Vec<Subtree>) {
+        let mut top = Vec::new();
+        let mut subtrees = Vec::new();
+        self.split_node(0, 1, level, &mut top, &mut subtrees);
+        (top, subtrees)
+    }
+
+    /// Adds the node starting at `parent`, at level `level`, to `top`, and returns where it starts in `top`
+    fn split_node(
+        &self,
+        parent: usize,
+        level: u32,
+        split_level: u32,
+        top: &mut Vec<u32>,
+        subtrees: &mut Vec<Subtree>,
+    ) -> usize {
+        let start = top.len();
+        top.extend(&self[parent..parent + 8]);
+        for uidx in 0..8 {
+            let node = self[parent + uidx];
+            if node & 1 > 0 {
+                let child = parent + (node >> 1) as usize;
+                if level + 1 >= split_level {
+                    top[start + uidx] = 0;
+                    subtrees.push((start + uidx, start, self.compact_node(child)));
+                } else {
+                    let ptr = self.split_node(child, level + 1, split_level, top, subtrees);
+                    top[start + uidx] = ((ptr - start) << 1 | 1) as u32;
+                }
+            }
+        }
+        start
+    }
+
+    /// Returns a coarser version of this chunk, with the bottom `levels` levels of full-size blocks removed.
+    /// Micro-blocks are always merged unless `levels` is 0, which returns the chunk unchanged.
+    /// Each subtree that gets cut off is replaced by a leaf of whichever material takes up the most space in it,
+    /// preferring solid materials in a tie so thin surfaces don't disappear.
+    pub fn downsample(&self, levels: u32) -> Chunk {
+        if levels == 0 {
+            return self.clone();
+        }
+        let max_level = BLOCK_LEVEL.saturating_sub(levels).max(1);
+        Chunk(self.downsample_node(0, 1, max_level))
+    }
+
+    /// Returns the node starting at `parent`, at level `level`, followed by all its descendants, downsampled
+    fn downsample_node(&self, parent: usize, level: u32, max_level: u32) -> Vec<u32> {
+        let mut ret = vec![0; 8];
+        for uidx in 0..8 {
+            let node = self[parent + uidx];
+            if node & 1 == 0 {
+                ret[uidx] = node;
+            } else if level >= max_level {
+                let mut volumes = HashMap::new();
+                self.leaf_volumes(parent + (node >> 1) as usize, 1.0, &mut volumes);
+                ret[uidx] = volumes
+                    .into_iter()
+                    .max_by(|(a, x), (b, y)| {
+                        x.partial_cmp(y).unwrap().then((*a != 0).cmp(&(*b != 0)))
+                    })
+                    .map_or(0, |(leaf, _)| leaf);
+            } else {
+                let child =
+                    self.downsample_node(parent + (node >> 1) as usize, level + 1, max_level);
+                push_child(&mut ret, uidx, child);
+            }
         }
+        ret
+    }
+
+    /// Adds up how much space each leaf value takes up under the node starting at `parent`, which has volume `volume`
+    fn leaf_volumes(&self, parent: usize, volume: f32, volumes: &mut HashMap<u32, f32>) {
+        let volume = volume / 8.0;
+        for uidx in 0..8 {
+            let node = self[parent + uidx];
+            if node & 1 > 0 {
+                self.leaf_volumes(parent + (node >> 1) as usize, volume, volumes);
+            } else {
+                *volumes.entry(node).or_insert(0.0) += volume;
+            }
+        }
+    }
+
+    /// Rebuilds the tree in canonical form: nodes whose eight children are identical leaves are merged,
+    /// unreachable nodes are dropped, and the rest are laid out depth-first in child order.
+    /// Two chunks with the same contents are identical after being compacted.
+    pub fn compact(&mut self) {
+        self.0 = self.compact_node(0);
+    }
+
+    /// Returns the node starting at `parent` followed by all its descendants, compacted
+    fn compact_node(&self, parent: usize) -> Vec<u32> {
+        let mut ret = vec![0; 8];
+        for uidx in 0..8 {
+            let node = self[parent + uidx];
+            if node & 1 > 0 {
+                let child = self.compact_node(parent + (node >> 1) as usize);
+                push_child(&mut ret, uidx, child);
+            } else {
+                ret[uidx] = node;
+            }
+        }
+        ret
+    }
+
+    /// Checks that every pointer stays inside the chunk and leads further in, that the tree is no deeper than `MAX_LEVELS`,
+    /// and that every leaf is a real material and state.
+    /// Chunks from disk or the network should be validated before anything traverses them.
+    pub fn validate(&self) -> Result<(), ChunkError> {
+        if self.len() < 8 {
+            return Err(ChunkError::TooShort(self.len()));
+        }
+
+        // The deepest level we've checked each node at. Nodes can be shared, so we might see one more than once.
+        let mut checked: HashMap<usize, u32> = HashMap::new();
+        // (start of node, level)
+        let mut stack = vec![(0, 1)];
+        while let Some((parent, level)) = stack.pop() {
+            match checked.get(&parent) {
+                Some(&l) if l >= level => continue,
+                _ => (),
+            }
+            checked.insert(parent, level);
+
+            for slot in parent..parent + 8 {
+                let node = self[slot];
+                if node & 1 > 0 {
+                    let target = parent + (node >> 1) as usize;
+                    if target == parent {
+                        return Err(ChunkError::Cycle { slot });
+                    }
+                    if target + 8 > self.len() {
+                        return Err(ChunkError::OutOfBounds { slot, target });
+                    }
+                    if level >= MAX_LEVELS {
+                        return Err(ChunkError::TooDeep { slot });
+                    }
+                    stack.push((target, level + 1));
+                } else if Block::decode(node).is_none() {
+                    return Err(ChunkError::BadMaterial {
+                        slot,
+                        id: node >> 1,
+                    });
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn empty() -> Self {
         Chunk(vec![0; 8])
     }
 
+    /// Builds a compacted octree from a flat array of blocks, indexed with `dense_idx()`
+    pub fn from_dense(dense: &[Material; CHUNK_VOLUME]) -> Self {
+        Chunk(Chunk::dense_node(dense, Vector3::zeros(), CHUNK_BLOCKS))
+    }
+
+    /// Returns the node covering the `size`-block cube starting at block `min`, followed by all its descendants
+    fn dense_node(dense: &[Material; CHUNK_VOLUME], min: Vector3<usize>, size: usize) -> Vec<u32> {
+        let size = size / 2; // Child size
+        let mut ret = vec![0; 8];
+        for uidx in 0..8 {
+            let min = min + idx_to_pos(uidx).map(|x| if x > 0.0 { size } else { 0 });
+            if size == 1 {
+                ret[uidx] = Block::from(dense[dense_idx(min)]).leaf();
+            } else {
+                push_child(&mut ret, uidx, Chunk::dense_node(dense, min, size));
+            }
+        }
+        ret
+    }
+
+    /// Flattens the octree into an array of blocks, indexed with `dense_idx()`.
+    /// Micro-blocks are flattened into the full-size block they're in.
+    pub fn to_dense(&self) -> [Material; CHUNK_VOLUME] {
+        let mut dense = [Material::Air; CHUNK_VOLUME];
+        for leaf in self.leaves() {
+            let min = leaf.min.map(|x| (x + CHUNK_SIZE * 0.5) as usize);
+            let size = (leaf.size as usize).max(1);
+            for x in min.x..min.x + size {
+                for y in min.y..min.y + size {
+                    for z in min.z..min.z + size {
+                        dense[dense_idx(Vector3::new(x, y, z))] = leaf.mat;
+                    }
+                }
+            }
+        }
+        dense
+    }
+
     pub fn from_dist(mut dist: impl FnMut(Vector3<f32>) -> (f32, Material)) -> Self {
         struct ST {
             parent: usize,
@@ -257,14 +855,14 @@ impl Chunk {
                     if d > size * d_corner {
                         v[j] = 0;
                     } else {
-                        v[j] = (mat as u32) << 1;
+                        v[j] = Block::from(mat).leaf();
                     }
                 } else if d > size * d_corner {
                     //v.leaf[j] = true;
                     v[j] = 0;
                 } else if d < -size * d_corner {
                     //v.leaf[j] = true;
-                    v[j] = (mat as u32) << 1;
+                    v[j] = Block::from(mat).leaf();
                 } else {
                     stack.push(ST {
                         parent: i * 8,
@@ -280,7 +878,22 @@ impl Chunk {
             }
             tree.append(&mut v);
         }
-        Chunk(tree)
+        let mut chunk = Chunk(tree);
+        chunk.compact();
+        chunk
+    }
+}
+
+/// Puts `child`, a compacted node followed by its descendants, in slot `uidx` of the node at the start of `ret`.
+/// If all eight of its children are the same leaf, it's merged into a leaf.
+fn push_child(ret: &mut Vec<u32>, uidx: usize, child: Vec<u32>) {
+    if child.len() == 8 && child.iter().all(|&x| x == child[0]) {
+        ret[uidx] = child[0];
+    } else {
+        // Relative pointer to the new child node
+        let ptr = ret.len();
+        ret.extend(child);
+        ret[uidx] = (ptr << 1 | 1) as u32;
     }
 }
 
@@ -316,3 +929,421 @@ pub fn neighbors(idx: Vector3<i32>) -> Vec<Vector3<i32>> {
     .map(|x| idx + x)
     .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sphere() -> Chunk {
+        Chunk::from_dist(|p| {
+            (
+                (p - Vector3::repeat(CHUNK_SIZE * 0.5)).norm() - 5.0,
+                Material::Stone,
+            )
+        })
+    }
+
+    #[test]
+    fn edits_compact_to_fresh_tree() {
+        let fresh = sphere();
+        let mut edited = sphere();
+        let blocks = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(-3.0, 2.0, 1.0),
+            Vector3::new(7.0, -8.0, 4.0),
+            Vector3::new(-8.0, -8.0, -8.0),
+        ];
+        for &b in &blocks {
+            edited.set_block(b, BLOCK_LEVEL, Material::Air);
+            edited.set_block(b + Vector3::x(), BLOCK_LEVEL, Material::Grass);
+        }
+        for &b in blocks.iter().rev() {
+            edited.set_block(b + Vector3::x(), BLOCK_LEVEL, fresh.block(b + Vector3::x()));
+            edited.set_block(b, BLOCK_LEVEL, fresh.block(b));
+        }
+        edited.compact();
+        assert_eq!(edited.0, fresh.0);
+    }
+
+    #[test]
+    fn set_block_merges_nodes() {
+        let mut chunk = Chunk::empty();
+        for x in 0..2 {
+            for y in 0..2 {
+                for z in 0..2 {
+                    chunk.set_block(
+                        Vector3::new(x as f32, y as f32, z as f32),
+                        4,
+                        Material::Dirt,
+                    );
+                }
+            }
+        }
+        // The eight blocks make up one 2x2x2 node, which should have been merged
+        assert_eq!(chunk.len(), 3 * 8);
+
+        for _ in 0..100 {
+            chunk.set_block(Vector3::new(5.0, 5.0, 5.0), BLOCK_LEVEL, Material::Stone);
+            chunk.set_block(Vector3::new(5.0, 5.0, 5.0), BLOCK_LEVEL, Material::Air);
+        }
+        assert_eq!(chunk.len(), 3 * 8);
+    }
+
+    #[test]
+    fn edit_matches_set_block() {
+        let shape = Sphere::new(Vector3::new(1.0, -2.0, 0.5), 4.5);
+        let mut edited = sphere();
+        assert!(edited.edit(&shape, |_| Material::Sand));
+
+        let mut expected = sphere();
+        for x in -8..8 {
+            for y in -8..8 {
+                for z in -8..8 {
+                    let p = Vector3::new(x as f32, y as f32, z as f32);
+                    if shape.contains(p + Vector3::repeat(0.5)) {
+                        expected.set_block(p, BLOCK_LEVEL, Material::Sand);
+                    }
+                }
+            }
+        }
+        expected.compact();
+        assert_eq!(edited.0, expected.0);
+
+        // Replacing only touches matching blocks
+        let all = Aabb::cube(Vector3::zeros(), CHUNK_SIZE);
+        let replace = |m| {
+            if m == Material::Sand {
+                Material::Air
+            } else {
+                m
+            }
+        };
+        assert!(edited.edit(&all, replace));
+        assert!(!edited.edit(&all, replace));
+        assert_eq!(edited.block(shape.center), Material::Air);
+        assert_eq!(edited.block(Vector3::new(-4.0, 0.0, 0.0)), Material::Stone);
+
+        // Filling the whole chunk merges it all into the root
+        assert!(edited.leaves().all(|l| l.mat != Material::Sand));
+        edited.edit(&all, |_| Material::Dirt);
+        assert_eq!(edited.0, vec![(Material::Dirt as u32) << 1; 8]);
+    }
+
+    #[test]
+    fn leaves_cover_blocks() {
+        let chunk = sphere();
+        let volume: f32 = chunk.leaves().map(|l| l.size.powi(3)).sum();
+        let mut count = 0;
+        for x in -8..8 {
+            for y in -8..8 {
+                for z in -8..8 {
+                    if chunk.block(Vector3::new(x as f32, y as f32, z as f32)) != Material::Air {
+                        count += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(volume as usize, count);
+
+        let bounds = Aabb::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(2.0, 3.0, 2.0));
+        let leaves: Vec<_> = chunk.leaves_in(bounds).collect();
+        assert!(!leaves.is_empty());
+        for l in leaves {
+            assert!(bounds.intersects(&Aabb::cube(l.min + Vector3::repeat(l.size * 0.5), l.size)));
+        }
+    }
+
+    /// A deterministic jumble of materials, in clumps so some nodes get merged
+    fn noise_dense(seed: u32) -> [Material; CHUNK_VOLUME] {
+        use enum_iterator::IntoEnumIterator;
+        let mats: Vec<Material> = Material::into_enum_iter().collect();
+        let mut dense = [Material::Air; CHUNK_VOLUME];
+        let mut state = seed;
+        for x in (0..CHUNK_BLOCKS).step_by(2) {
+            for y in 0..CHUNK_BLOCKS {
+                for z in (0..CHUNK_BLOCKS).step_by(2) {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    let mat = if y > x + z {
+                        Material::Air
+                    } else {
+                        mats[(state >> 16) as usize % mats.len()]
+                    };
+                    for i in 0..4 {
+                        dense[dense_idx(Vector3::new(x + i / 2, y, z + i % 2))] = mat;
+                    }
+                }
+            }
+        }
+        dense
+    }
+
+    #[test]
+    fn dense_round_trip() {
+        for seed in 0..8 {
+            let dense = noise_dense(seed);
+            let chunk = Chunk::from_dense(&dense);
+            assert!(chunk.len() < CHUNK_VOLUME);
+            assert!(chunk.to_dense().iter().eq(dense.iter()));
+
+            // `block()` follows the pointers the same way the shader does
+            for x in 0..CHUNK_BLOCKS {
+                for y in 0..CHUNK_BLOCKS {
+                    for z in 0..CHUNK_BLOCKS {
+                        let p = Vector3::new(x, y, z);
+                        let pos = p.map(|x| x as f32 + 0.5 - CHUNK_SIZE * 0.5);
+                        assert_eq!(chunk.block(pos), dense[dense_idx(p)]);
+                    }
+                }
+            }
+
+            let mut copy = chunk.clone();
+            copy.compact();
+            assert_eq!(copy.0, chunk.0);
+        }
+
+        let chunk = sphere();
+        assert_eq!(Chunk::from_dense(&chunk.to_dense()).0, chunk.0);
+    }
+
+    #[test]
+    fn validate_catches_bad_chunks() {
+        assert_eq!(sphere().validate(), Ok(()));
+        assert_eq!(Chunk::from_dense(&noise_dense(3)).validate(), Ok(()));
+
+        assert_eq!(Chunk(vec![0; 5]).validate(), Err(ChunkError::TooShort(5)));
+
+        let mut chunk = Chunk::empty();
+        chunk[3] = 1;
+        assert_eq!(chunk.validate(), Err(ChunkError::Cycle { slot: 3 }));
+
+        chunk[3] = 8 << 1 | 1;
+        assert_eq!(
+            chunk.validate(),
+            Err(ChunkError::OutOfBounds { slot: 3, target: 8 })
+        );
+
+        chunk[3] = 1000 << 1;
+        assert_eq!(
+            chunk.validate(),
+            Err(ChunkError::BadMaterial { slot: 3, id: 1000 })
+        );
+
+        // Every node points to the next one, MAX_LEVELS + 1 deep
+        let mut chunk = Chunk(vec![0; 8 * (MAX_LEVELS as usize + 1)]);
+        for i in 0..MAX_LEVELS as usize {
+            chunk[i * 8] = 8 << 1 | 1;
+        }
+        assert_eq!(
+            chunk.validate(),
+            Err(ChunkError::TooDeep {
+                slot: 8 * (MAX_LEVELS as usize - 1)
+            })
+        );
+    }
+
+    #[test]
+    fn micro_blocks() {
+        assert_eq!(2.0_f32.powi(BLOCK_LEVEL as i32), CHUNK_SIZE);
+
+        let mut chunk = Chunk::empty();
+        let p = Vector3::new(2.0, 3.0, -4.0);
+        chunk.set_block(p, MAX_BLOCK_LEVEL, Material::Leaf);
+        chunk.set_block(p + Vector3::repeat(0.5), BLOCK_LEVEL + 1, Material::Wood);
+        assert_eq!(chunk.validate(), Ok(()));
+
+        assert_eq!(chunk.block(p + Vector3::repeat(0.1)), Material::Leaf);
+        assert_eq!(chunk.block(p + Vector3::repeat(0.3)), Material::Air);
+        assert_eq!(chunk.block(p + Vector3::repeat(0.7)), Material::Wood);
+
+        let hit = chunk
+            .raycast(
+                p + Vector3::new(0.125, 0.125, -3.0),
+                Vector3::new(0.0001, 0.0001, 1.0),
+                64,
+            )
+            .unwrap();
+        assert_eq!(hit.mat, Material::Leaf);
+        assert_eq!(hit.size, 0.25);
+        assert_eq!(hit.pos, p + Vector3::repeat(0.125));
+
+        let dense = chunk.to_dense();
+        let idx = dense_idx(p.map(|x| (x + CHUNK_SIZE * 0.5) as usize));
+        assert_ne!(dense[idx], Material::Air);
+    }
+
+    #[test]
+    fn downsample_removes_levels() {
+        let chunk = sphere();
+        assert_eq!(chunk.downsample(0).0, chunk.0);
+
+        let lod = chunk.downsample(2);
+        assert_eq!(lod.validate(), Ok(()));
+        assert!(lod.len() < chunk.len());
+        assert!(lod.leaves().all(|l| l.size >= 4.0));
+        // The center of the sphere is still solid
+        assert_eq!(lod.block(Vector3::zeros()), Material::Stone);
+        assert_eq!(lod.block(Vector3::repeat(-7.5)), Material::Air);
+
+        let mut chunk = Chunk::empty();
+        chunk.set_block(Vector3::zeros(), BLOCK_LEVEL, Material::Grass);
+        chunk.set_block(Vector3::x(), BLOCK_LEVEL, Material::Stone);
+        chunk.set_block(Vector3::y(), BLOCK_LEVEL, Material::Stone);
+        // Air still takes up most of the 2x2x2 node
+        assert_eq!(chunk.downsample(1).block(Vector3::zeros()), Material::Air);
+        chunk.set_block(Vector3::z(), BLOCK_LEVEL, Material::Stone);
+        chunk.set_block(Vector3::new(1.0, 1.0, 0.0), BLOCK_LEVEL, Material::Stone);
+        assert_eq!(chunk.downsample(1).block(Vector3::zeros()), Material::Stone);
+        assert_eq!(chunk.downsample(1).len(), 3 * 8);
+    }
+
+    #[test]
+    fn downsample_micro_blocks() {
+        let mut chunk = Chunk::empty();
+        let p = Vector3::new(2.0, 3.0, -4.0);
+        chunk.set_block(p, MAX_BLOCK_LEVEL, Material::Leaf);
+        chunk.set_block(p + Vector3::repeat(0.5), BLOCK_LEVEL + 1, Material::Wood);
+        assert_eq!(chunk.downsample(0).0, chunk.0);
+        assert_eq!(
+            chunk.downsample(0).block(p + Vector3::repeat(0.1)),
+            Material::Leaf
+        );
+
+        let lod = chunk.downsample(1);
+        assert_eq!(lod.validate(), Ok(()));
+        assert!(lod.leaves().all(|l| l.size >= 2.0));
+    }
+
+    #[test]
+    fn split_rejoins() {
+        let chunk = sphere();
+        let (mut top, subtrees) = chunk.split(3);
+        assert!(!subtrees.is_empty());
+        for (slot, parent, subtree) in subtrees {
+            assert_eq!(top[slot], 0);
+            top[slot] = ((top.len() - parent) << 1 | 1) as u32;
+            top.extend(subtree);
+        }
+        let mut joined = Chunk(top);
+        assert_eq!(joined.validate(), Ok(()));
+        joined.compact();
+        assert_eq!(joined.0, chunk.0);
+    }
+
+    #[test]
+    fn shared_chunk_reads_like_chunk() {
+        let chunk = sphere();
+        let (top, subtrees) = chunk.split(3);
+        let mut shared = SharedChunk::new(
+            top,
+            subtrees
+                .into_iter()
+                .map(|(slot, parent, subtree)| (slot, parent, subtree.into())),
+        );
+        let mut flat = chunk.clone();
+        assert_eq!(shared.len(), shared.to_chunk().len());
+        assert!(shared.leaves().eq(chunk.leaves()));
+        for &p in &[
+            Vector3::new(0.5, 0.5, 0.5),
+            Vector3::new(-3.5, 2.5, 1.5),
+            Vector3::new(7.5, -7.5, 4.5),
+        ] {
+            assert_eq!(shared.get_block(p), chunk.get_block(p));
+        }
+        let ro = Vector3::new(-8.0, 0.3, 0.2);
+        let rd = Vector3::new(1.0, 0.1, 0.05).normalize();
+        assert_eq!(
+            shared.raycast(ro, rd, 64).map(|x| x.voxel),
+            chunk.raycast(ro, rd, 64).map(|x| x.voxel)
+        );
+
+        // Editing it stops sharing, but keeps the same blocks
+        shared
+            .make_mut()
+            .set_block(Vector3::zeros(), BLOCK_LEVEL, Material::Wood);
+        flat.set_block(Vector3::zeros(), BLOCK_LEVEL, Material::Wood);
+        assert_eq!(shared.to_chunk().to_dense()[..], flat.to_dense()[..]);
+    }
+
+    #[test]
+    fn block_states() {
+        let state = BlockState::default()
+            .with_facing(Facing::South)
+            .with_level(9)
+            .with_tint(200);
+        assert_eq!(state.facing(), Facing::South);
+        assert_eq!(state.level(), 9);
+        assert_eq!(state.tint(), 200);
+        let log = Block::new(Material::Wood, state);
+        assert_eq!(Block::decode(log.leaf()), Some(log));
+        // Air always encodes as empty space
+        assert_eq!(Block::new(Material::Air, state).leaf(), 0);
+
+        let mut chunk = sphere();
+        let p = Vector3::new(2.0, 2.0, 2.0);
+        chunk.set_block(p, BLOCK_LEVEL, log);
+        assert_eq!(chunk.get_block(p), log);
+        assert_eq!(chunk.block(p), Material::Wood);
+        assert_eq!(chunk.validate(), Ok(()));
+        let hit = chunk
+            .raycast(Vector3::repeat(-8.0), Vector3::repeat(1.0), 64)
+            .unwrap();
+        assert_eq!(hit.state, BlockState::default());
+
+        // Edits keep the state unless the material changes
+        chunk.edit(&Aabb::cube(p, 4.0), |m| m);
+        assert_eq!(chunk.get_block(p), log);
+        chunk.edit(&Aabb::cube(p, 4.0), |_| Material::Stone);
+        assert_eq!(chunk.get_block(p), Material::Stone.into());
+
+        // A leaf of Air with a state isn't valid
+        chunk[0] = 1 << (MAT_BITS + 1);
+        assert!(chunk.validate().is_err());
+    }
+
+    #[test]
+    fn raycast_faces() {
+        // The bottom half of the chunk is stone, so it's one big leaf
+        let mut chunk = Chunk::empty();
+        for uidx in 0..8 {
+            if idx_to_pos(uidx).y < 0.0 {
+                chunk[uidx] = Block::from(Material::Stone).leaf();
+            }
+        }
+
+        let hit = chunk
+            .raycast(Vector3::new(2.5, 4.0, -3.5), -Vector3::y(), 64)
+            .unwrap();
+        assert_eq!(hit.size, 8.0);
+        assert_eq!(hit.normal, Vector3::y());
+        assert_eq!(hit.voxel, Vector3::new(2, -1, -4));
+        assert_eq!(hit.adjacent, Vector3::new(2, 0, -4));
+
+        let rd = Vector3::new(-1.0, -0.5, 0.0001);
+        let hit = chunk.raycast(Vector3::new(7.5, 0.5, 0.5), rd, 64).unwrap();
+        assert_eq!(hit.normal, Vector3::y());
+        assert_eq!(hit.voxel, Vector3::new(6, -1, 0));
+
+        // Water on top gets skipped by the filter
+        chunk.set_block(Vector3::new(2.0, 0.0, -4.0), BLOCK_LEVEL, Material::Water);
+        let ro = Vector3::new(2.5, 4.0, -3.5);
+        let hit = chunk.raycast(ro, -Vector3::y(), 64).unwrap();
+        assert_eq!(hit.mat, Material::Water);
+        assert_eq!(hit.voxel, Vector3::new(2, 0, -4));
+        let hit = chunk
+            .raycast_filter(ro, -Vector3::y(), 64, |m| m != Material::Water)
+            .unwrap();
+        assert_eq!(hit.mat, Material::Stone);
+        assert_eq!(hit.voxel, Vector3::new(2, -1, -4));
+        assert_eq!(hit.adjacent, Vector3::new(2, 0, -4));
+
+        // A micro-block on the side of a block
+        chunk.set_block(Vector3::new(4.0, 0.0, 4.0), MAX_BLOCK_LEVEL, Material::Sand);
+        let hit = chunk
+            .raycast(Vector3::new(4.1, 0.1, 7.0), -Vector3::z(), 64)
+            .unwrap();
+        assert_eq!(hit.size, 0.25);
+        assert_eq!(hit.normal, Vector3::z());
+        assert_eq!(hit.voxel, Vector3::new(4, 0, 4));
+        assert_eq!(hit.adjacent, Vector3::new(4, 0, 4));
+    }
+}
@@ -1,57 +1,412 @@
 use crate::chunk_thread::*;
+use crate::command::*;
 use crate::common::*;
 use crate::config::*;
 use crate::world::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufRead;
+use std::io::Write;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::*;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 struct Player {
     pos: Vector3<f32>,
+    /// Which way they're looking
+    dir: Vector3<f32>,
     conn: Rc<Connection>,
     id: usize,
+    name: String,
+    /// How far this player can see, in chunks. It's never more than the server's `draw_chunks`
+    draw_chunks: usize,
+    /// The chunks we've sent this player in low detail
+    lod: HashSet<Vector3<i32>>,
+    /// Which commands they can run
+    permission: Permission,
+}
+
+impl Player {
+    /// Downsamples the chunks that are too far from this player to need full detail, and keeps track of which ones they are
+    fn prepare(
+        &mut self,
+        config: &GameConfig,
+        chunks: Vec<(Vector3<i32>, Chunk)>,
+    ) -> Vec<(Vector3<i32>, Chunk)> {
+        let c = world_to_chunk(self.pos);
+        chunks
+            .into_iter()
+            .map(|(i, chunk)| {
+                if (c - i).map(|x| x as f32).norm() > config.lod_chunks as f32 {
+                    self.lod.insert(i);
+                    (i, chunk.downsample(config.lod_levels))
+                } else {
+                    self.lod.remove(&i);
+                    (i, chunk)
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the chunks we sent in low detail that are now close enough to need full detail.
+    /// The client replaces the low-detail version when it gets the new one.
+    fn upgrades(&mut self, config: &GameConfig) -> Vec<Vector3<i32>> {
+        let c = world_to_chunk(self.pos);
+        let dist = |i: &Vector3<i32>| (c - i).map(|x| x as f32).norm();
+        // The client will have dropped chunks that are out of range
+        let draw_chunks = self.draw_chunks as f32;
+        self.lod.retain(|i| dist(i) <= draw_chunks);
+        let close: Vec<_> = self
+            .lod
+            .iter()
+            .filter(|i| dist(i) <= config.lod_chunks as f32)
+            .cloned()
+            .collect();
+        for i in &close {
+            self.lod.remove(i);
+        }
+        close
+    }
+}
+
+/// The most blocks `/fill` can change at once
+const MAX_FILL: i64 = 64 * 64 * 64;
+
+/// How often the server saves everything that's changed, in ticks
+const AUTOSAVE_TICKS: u64 = 60 * TICK_RATE;
+
+/// How often the server reminds clients what time it is, in ticks
+const TIME_UPDATE_TICKS: u64 = TICK_RATE;
+
+/// Everything about a world that isn't in its chunks, saved in `world.ron` in the world directory
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct WorldInfo {
+    /// How many ticks of game time have passed since the world was created. It doesn't change while the time is frozen
+    time: u64,
+    time_frozen: bool,
+}
+
+impl WorldInfo {
+    /// The time of day, from 0 to 1, where 0 is noon, if a day is `day` ticks long
+    fn time_of_day(&self, day: u64) -> f64 {
+        (self.time % day) as f64 / day as f64
+    }
+
+    /// Moves the clock forwards to `time` of day, where a day is `day` ticks long
+    fn set_time_of_day(&mut self, time: f64, day: u64) {
+        let target = (time.rem_euclid(1.0) * day as f64) as u64;
+        let now = self.time % day;
+        self.time += (target + day - now) % day;
+    }
+}
+
+/// Stops a running server from another thread.
+/// The server saves the world and disconnects everybody before `run()` returns.
+#[derive(Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether somebody's already asked the server to stop
+    pub fn stopping(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
 pub struct Server {
     world: ArcWorld,
     refs: HashMap<Vector3<i32>, usize>,
     players: Vec<Player>,
+    /// The id the next player to join gets. Ids aren't reused, even after players leave
+    next_id: usize,
     orders: HashMap<Vector3<i32>, Vec<(usize, Rc<Connection>)>>,
+    /// Connections that haven't sent a `Hello` yet
+    connecting: Vec<Connection>,
+    /// Chunks that have been edited since they were last saved
+    dirty: HashSet<Vector3<i32>>,
     ch: (Sender<ChunkMessage>, Receiver<ChunkMessage>),
+    /// We wait for this when we're dropped, so it can finish saving
+    chunk_thread: Option<thread::JoinHandle<()>>,
+    /// Players connecting over TCP, from the thread started by `listen()`
+    incoming: Option<Receiver<Connection>>,
+    /// Lines typed into the console, from the thread started by `console()`
+    console: Option<Receiver<String>>,
+    /// How long the last second of ticks took
+    tick_times: VecDeque<Duration>,
+    /// How many regions the chunk thread has cached
+    cached_regions: Arc<AtomicUsize>,
+    stop: StopHandle,
+    /// How many ticks have run since the server started.
+    /// Anything that happens over time in the game should count ticks rather than look at the clock, so it runs at the same speed when the server falls behind.
+    ticks: u64,
+    info: WorldInfo,
+    world_dir: PathBuf,
     config: Arc<GameConfig>,
 }
 
+impl Drop for Server {
+    /// Waits for the chunk thread to write everything to disk, even if we're unwinding from a panic
+    fn drop(&mut self) {
+        // Replacing our end of the channel hangs up on the chunk thread, which makes it flush its cache and stop
+        self.ch.0 = channel().0;
+        if let Some(t) = self.chunk_thread.take() {
+            t.join().ok();
+        }
+    }
+}
+
 impl Server {
-    /// Creates and starts a chunk thread, and creates a Server
-    pub fn new(config: Arc<GameConfig>) -> Self {
+    /// Creates and starts a chunk thread, and creates a Server.
+    /// The world is loaded from and saved to `world_dir`.
+    pub fn new(config: Arc<GameConfig>, world_dir: PathBuf) -> Self {
         let (to, from_them) = channel();
         let (to_them, from) = channel();
         let c = Arc::clone(&config);
         let world = arcworld();
         let wc = Arc::clone(&world);
 
-        thread::spawn(move || ChunkThread::new(c, wc, to_them, from_them).run());
+        let info_file = world_dir.join("world.ron");
+        let info = if info_file.exists() {
+            // Like a corrupt region, a bad world.ron shouldn't stop the world from loading
+            File::open(&info_file)
+                .map_err(|e| e.to_string())
+                .and_then(|f| ron::de::from_reader(f).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    println!(
+                        "WARNING: couldn't load world.ron, starting the clock again: {}",
+                        e
+                    );
+                    WorldInfo::default()
+                })
+        } else {
+            WorldInfo::default()
+        };
+
+        let wd = world_dir.clone();
+        let cached_regions = Arc::new(AtomicUsize::new(0));
+        let cr = Arc::clone(&cached_regions);
+        let chunk_thread =
+            thread::spawn(move || ChunkThread::new(c, wc, wd, cr, to_them, from_them).run());
 
         Server {
             world,
             refs: HashMap::new(),
             players: Vec::new(),
+            next_id: 0,
             orders: HashMap::new(),
+            connecting: Vec::new(),
+            dirty: HashSet::new(),
             ch: (to, from),
+            chunk_thread: Some(chunk_thread),
+            incoming: None,
+            console: None,
+            tick_times: VecDeque::new(),
+            cached_regions,
+            stop: StopHandle(Arc::new(AtomicBool::new(false))),
+            ticks: 0,
+            info,
+            world_dir,
             config,
         }
     }
 
-    /// Add a player to the game
-    pub fn join(&mut self, conn: Connection, pos: Vector3<f32>) {
-        let new_player = Player {
+    /// A handle that can stop this server once it's running
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// How long a day is, in ticks
+    fn day_ticks(&self) -> u64 {
+        ((self.config.day_length * TICK_RATE as f64) as u64).max(1)
+    }
+
+    /// The current time of day, from 0 to 1, where 0 is noon
+    pub fn time_of_day(&self) -> f64 {
+        self.info.time_of_day(self.day_ticks())
+    }
+
+    /// Sets the time of day, from 0 to 1, where 0 is noon. It's always later than it was, so things that count ticks don't go backwards
+    pub fn set_time_of_day(&mut self, time: f64) {
+        let day = self.day_ticks();
+        self.info.set_time_of_day(time, day);
+        self.send_time();
+    }
+
+    /// Stops or starts the clock
+    pub fn freeze_time(&mut self, frozen: bool) {
+        self.info.time_frozen = frozen;
+        self.send_time();
+    }
+
+    /// The `Message::Time` that tells clients what time it is now
+    fn time_message(&self) -> Message {
+        Message::Time {
+            day: self.time_of_day(),
+            speed: if self.info.time_frozen {
+                0.0
+            } else {
+                1.0 / self.config.day_length
+            },
+        }
+    }
+
+    /// Tells every player what time it is
+    fn send_time(&self) {
+        for p in &self.players {
+            p.conn.send(self.time_message());
+        }
+    }
+
+    /// Saves the world time and anything else that isn't in a chunk
+    fn save_info(&self) {
+        if !self.config.save_chunks {
+            return;
+        }
+        let s = ron::ser::to_string(&self.info).unwrap();
+        match File::create(self.world_dir.join("world.ron")).and_then(|mut f| writeln!(f, "{}", s))
+        {
+            Ok(()) => (),
+            Err(e) => println!("WARNING: couldn't save world.ron: {}", e),
+        }
+    }
+
+    /// Starts a thread that accepts players over TCP on `addr`. They join once they've sent a `Hello`.
+    pub fn listen(&mut self, addr: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        println!("Listening on {}", listener.local_addr()?);
+        let (to, from) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let conn = stream.and_then(|stream| {
+                    println!("{} connected", stream.peer_addr()?);
+                    Connection::tcp(stream)
+                });
+                match conn {
+                    Ok(conn) => {
+                        if to.send(conn).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => println!("WARNING: couldn't accept a connection: {}", e),
+                }
+            }
+        });
+        self.incoming = Some(from);
+        Ok(())
+    }
+
+    /// Starts a thread that reads commands from stdin, like `status` or `/tp Bob 0 40 0`.
+    /// They can do anything, and the results are printed.
+    pub fn console(&mut self) {
+        let (to, from) = channel();
+        thread::spawn(move || {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        println!("WARNING: couldn't read from the console: {}", e);
+                        break;
+                    }
+                };
+                if to.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        self.console = Some(from);
+    }
+
+    /// Add a player to the game. They'll join once they've sent a `Hello`.
+    pub fn join(&mut self, conn: Connection) {
+        self.connecting.push(conn);
+    }
+
+    /// Answers the `Hello`s that new connections have sent, letting in the ones that are compatible
+    fn handshakes(&mut self) {
+        for conn in std::mem::take(&mut self.connecting) {
+            match conn.recv() {
+                None => self.connecting.push(conn),
+                Some(Message::Hello {
+                    version,
+                    name,
+                    draw_chunks,
+                }) => {
+                    if version != PROTOCOL_VERSION {
+                        println!(
+                            "{} couldn't join: they're using protocol version {}, but we're using {}",
+                            name, version, PROTOCOL_VERSION
+                        );
+                        conn.send(Message::Rejected(format!(
+                            "The server is using protocol version {}, but you're using {}",
+                            PROTOCOL_VERSION, version
+                        )));
+                    } else if self.player(&name).is_ok() {
+                        // Commands find players by name, so there can't be two of the same one
+                        println!("{} couldn't join: somebody's already using that name", name);
+                        conn.send(Message::Rejected(format!(
+                            "There's already somebody called {} here",
+                            name
+                        )));
+                    } else {
+                        self.add_player(conn, name, draw_chunks);
+                    }
+                }
+                // They left before they joined
+                Some(Message::Leave) => (),
+                Some(_) => {
+                    conn.send(Message::Rejected(
+                        "The first message has to be a Hello".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Sends a new player a `Welcome` and the chunks around them
+    fn add_player(&mut self, conn: Connection, name: String, draw_chunks: usize) {
+        let pos = Vector3::zeros();
+        // Whoever's running the server in their own process can do anything with it.
+        // Names aren't checked, so nobody gets admin rights over TCP.
+        let permission = if matches!(conn, Connection::Local(..)) {
+            Permission::Admin
+        } else {
+            Permission::Player
+        };
+        let mut new_player = Player {
             pos,
+            dir: Vector3::z(),
             conn: Rc::new(conn),
-            id: self.players.len(),
+            id: self.next_id,
+            name,
+            draw_chunks: draw_chunks.min(self.config.draw_chunks),
+            lod: HashSet::new(),
+            permission,
         };
-        let (wait, load) = self.load_chunks_around(pos);
+        println!(
+            "{} joined with view distance {}",
+            new_player.name, new_player.draw_chunks
+        );
+        new_player.conn.send(Message::Welcome {
+            id: new_player.id,
+            pos,
+            seed: self.config.seed,
+            materials: Material::table(),
+            draw_chunks: new_player.draw_chunks,
+        });
+        new_player.conn.send(self.time_message());
+        let (wait, load) = self.load_chunks_around(pos, new_player.draw_chunks);
 
         for i in wait {
             self.orders
@@ -60,136 +415,570 @@ impl Server {
                 .push((new_player.id, Rc::clone(&new_player.conn)));
         }
         if !load.is_empty() {
-            new_player.conn.send(Message::Chunks(load)).unwrap();
+            let load = new_player.prepare(&self.config, load);
+            new_player.conn.send(Message::Chunks(load));
         }
+        self.next_id += 1;
         self.players.push(new_player);
     }
 
-    /// Runs an infinite tick loop. It's infinite, start as a new thread!
+    /// Releases the chunks a player was using and forgets the chunks they were waiting for, after they leave
+    fn remove_player(&mut self, p: &Player) {
+        println!("{} left", p.name);
+        self.release(chunks_around(world_to_chunk(p.pos), p.draw_chunks));
+        for v in self.orders.values_mut() {
+            v.retain(|(id, _)| *id != p.id);
+        }
+        self.orders.retain(|_, v| !v.is_empty());
+    }
+
+    /// Runs the tick loop until it's stopped with a `StopHandle`, so start it as a new thread!
+    /// Between ticks, we block on the chunk thread and send players their chunks as soon as they're loaded.
+    /// We don't wake up for players: their messages and new connections wait until the next tick,
+    /// so they're handled at most `TICK_TIME` late, and everything they do happens on a tick.
     pub fn run(mut self) {
-        let mut running = true;
-        while running {
-            let mut p = Vec::new();
-            std::mem::swap(&mut p, &mut self.players);
-            let mut change = false;
-            self.players = p
-                .into_iter()
-                .filter_map(|mut p| {
-                    let mut np = p.pos;
-                    while let Some(m) = p.conn.recv() {
-                        match m {
-                            Message::PlayerMove(n_pos) => {
-                                np = n_pos;
-                            }
-                            Message::Leave => match *p.conn {
-                                Connection::Local(_, _) => {
-                                    running = false;
-                                    break;
+        let mut next_tick = Instant::now();
+        while !self.stop.stopping() {
+            let start = Instant::now();
+            self.tick();
+            self.ticks += 1;
+            if !self.info.time_frozen {
+                self.info.time += 1;
+            }
+            if self.ticks % TIME_UPDATE_TICKS == 0 {
+                self.send_time();
+            }
+            if self.ticks % AUTOSAVE_TICKS == 0 {
+                self.save();
+            }
+
+            let took = start.elapsed();
+            self.tick_times.push_back(took);
+            if self.tick_times.len() > TICK_RATE as usize {
+                self.tick_times.pop_front();
+            }
+            next_tick += TICK_TIME;
+            if took > TICK_TIME {
+                println!(
+                    "WARNING: tick {} took {} ms, {} ms over budget",
+                    self.ticks,
+                    took.as_millis(),
+                    (took - TICK_TIME).as_millis()
+                );
+            }
+            // If we've fallen behind, don't run a bunch of ticks at once to catch up
+            if next_tick < Instant::now() {
+                next_tick = Instant::now();
+            }
+
+            loop {
+                let now = Instant::now();
+                if now >= next_tick {
+                    break;
+                }
+                match self.ch.1.recv_timeout(next_tick - now) {
+                    Ok(m) => self.chunk_message(m),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => panic!("Chunk thread stopped"),
+                }
+            }
+        }
+        self.unload_all();
+        self.save_info();
+        for p in &self.players {
+            p.conn.send(Message::Leave);
+        }
+    }
+
+    /// Saves everything that's changed, and makes sure the chunk thread writes it to disk
+    fn save(&mut self) {
+        self.save_dirty();
+        self.save_info();
+        self.ch.0.send(ChunkMessage::Flush).unwrap();
+    }
+
+    /// Lets in new players, and handles everything players have sent since the last tick
+    fn tick(&mut self) {
+        let joined: Vec<_> = self.incoming.iter().flat_map(|x| x.try_iter()).collect();
+        for conn in joined {
+            self.join(conn);
+        }
+        self.handshakes();
+
+        let mut p = Vec::new();
+        std::mem::swap(&mut p, &mut self.players);
+        let mut change = false;
+        let mut edited = Vec::new();
+        let mut chat = Vec::new();
+        let players: Vec<_> = p.iter().map(|x| x.pos).collect();
+        self.players = p
+            .into_iter()
+            .filter_map(|mut p| {
+                let mut np = p.pos;
+                while let Some(m) = p.conn.recv() {
+                    match m {
+                        Message::PlayerMove(n_pos, dir) => {
+                            np = n_pos;
+                            p.dir = dir;
+                        }
+                        Message::Leave => {
+                            self.remove_player(&p);
+                            // The chunk thread needs to know they're gone
+                            change = true;
+                            return None;
+                        }
+                        Message::SetBlock(id, pos, block) => {
+                            let loc = self.edit(np, &players, pos, block);
+                            if let Some(loc) = loc {
+                                if !edited.contains(&loc) {
+                                    edited.push(loc);
                                 }
-                                _ => return None,
-                            },
-                            // Message::SetBlock(p, b) => {
-                            //     self.world
-                            //         .write()
-                            //         .unwrap()
-                            //         .set_block(p.map(|x| x as f32), b);
-                            // }
-                            _ => panic!("Hey, a client sent a message {:?}", m),
+                            }
+                            p.conn.send(Message::EditResult(id, loc.is_some()));
                         }
+                        Message::Chat(text) => chat.push((p.id, text)),
+                        // Clients in other processes could send anything, so don't crash
+                        _ => println!("WARNING: a client sent a message {:?}", m),
                     }
-                    let (wait, load) = self.load_chunk_diff(p.pos, np);
-                    //p.to_send.append(&mut wait);
-                    if !change && (!wait.is_empty() || !load.is_empty()) {
-                        change = true;
-                    }
-                    for i in wait {
-                        self.orders
-                            .entry(i)
-                            .or_insert_with(Vec::new)
-                            .push((p.id, Rc::clone(&p.conn)));
-                    }
-                    if !load.is_empty() {
-                        p.conn.send(Message::Chunks(load)).unwrap();
+                }
+                let moved = world_to_chunk(p.pos) != world_to_chunk(np);
+                let (wait, mut load) = self.load_chunk_diff(p.pos, np, p.draw_chunks);
+                //p.to_send.append(&mut wait);
+                if !change && (!wait.is_empty() || !load.is_empty()) {
+                    change = true;
+                }
+                for i in wait {
+                    self.orders
+                        .entry(i)
+                        .or_insert_with(Vec::new)
+                        .push((p.id, Rc::clone(&p.conn)));
+                }
+                p.pos = np;
+                if moved {
+                    let world = self.world.read().unwrap();
+                    for i in p.upgrades(&self.config) {
+                        if let Some(chunk) = world.chunk(i) {
+                            load.push((i, chunk.clone()));
+                        }
                     }
-                    p.pos = np;
-                    Some(p)
-                })
-                .collect();
+                }
+                if !load.is_empty() {
+                    let load = p.prepare(&self.config, load);
+                    // If this fails they've disconnected, and we'll get a Leave from them soon
+                    p.conn.send(Message::Chunks(load));
+                }
+                Some(p)
+            })
+            .collect();
+
+        if !edited.is_empty() {
+            self.send_chunks(&edited);
+            self.save_dirty();
+        }
+
+        for (id, text) in chat {
+            self.chat(Some(id), text);
+        }
+        let lines: Vec<_> = self.console.iter().flat_map(|x| x.try_iter()).collect();
+        for line in lines {
+            // The console only runs commands, so the '/' is optional
+            let line = line.trim();
+            if !line.is_empty() {
+                self.chat(None, format!("/{}", line.trim_start_matches('/')));
+            }
+        }
+
+        self.send_players();
+
+        if change {
+            self.players_changed();
+        }
+    }
+
+    /// Forgets chunks nobody's waiting for anymore, and tells the chunk thread where everybody is
+    fn players_changed(&mut self) {
+        let p: Vec<Vector3<f32>> = self.players.iter().map(|x| x.pos).collect();
+        let keys: Vec<_> = self.orders.keys().cloned().collect();
+        for k in keys {
+            if !self.players.iter().any(|y| {
+                (world_to_chunk(y.pos) - k).map(|x| x as f32).norm() <= y.draw_chunks as f32
+            }) {
+                self.orders.remove(&k);
+            }
+        }
+        self.ch.0.send(ChunkMessage::Players(p)).unwrap();
+    }
+
+    /// Sends a line of text to a player, or prints it if it's for the console (`None`)
+    fn reply(&self, to: Option<usize>, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        match to.and_then(|id| self.players.iter().find(|p| p.id == id)) {
+            Some(p) => {
+                p.conn.send(Message::Chat(text));
+            }
+            None => println!("{}", text),
+        }
+    }
+
+    /// Handles a line of chat from a player, or from the console if `from` is `None`.
+    /// Commands start with a '/'; anything else goes to everybody.
+    fn chat(&mut self, from: Option<usize>, text: String) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        if text.starts_with('/') {
+            let result = self.command(from, text);
+            self.reply(from, result.unwrap_or_else(|e| format!("Error: {}", e)));
+        } else {
+            self.say(from, text);
+        }
+    }
+
+    /// Sends a line of chat to everybody, from a player or from the console if `from` is `None`
+    fn say(&self, from: Option<usize>, text: &str) {
+        let name = match from {
+            Some(id) => match self.players.iter().find(|p| p.id == id) {
+                Some(p) => p.name.clone(),
+                None => return,
+            },
+            None => "Server".to_string(),
+        };
+        let line = format!("<{}> {}", name, text);
+        println!("{}", line);
+        for p in &self.players {
+            p.conn.send(Message::Chat(line.clone()));
+        }
+    }
+
+    /// A summary of what the server's doing, for `/status`
+    fn status(&self) -> String {
+        let names: Vec<_> = self.players.iter().map(|p| p.name.as_str()).collect();
+        let total: Duration = self.tick_times.iter().sum();
+        let avg = total / self.tick_times.len().max(1) as u32;
+        let max = self.tick_times.iter().max().cloned().unwrap_or_default();
+        let waiting: usize = self.orders.values().map(|v| v.len()).sum();
+        format!(
+            "Players: {} [{}], {} connecting\n\
+             Chunks: {} in use, {} loaded, {} being loaded for {} requests\n\
+             Ticks: {} so far, {:.1} ms average and {:.1} ms max over the last second, out of {} ms\n\
+             Region cache: {}/{} regions",
+            self.players.len(),
+            names.join(", "),
+            self.connecting.len(),
+            self.refs.len(),
+            self.world.read().unwrap().chunks.len(),
+            self.orders.len(),
+            waiting,
+            self.ticks,
+            avg.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0,
+            TICK_TIME.as_millis(),
+            self.cached_regions.load(Ordering::Relaxed),
+            CACHE_SIZE,
+        )
+    }
 
-            if change {
-                let p: Vec<Vector3<f32>> = self.players.iter().map(|x| x.pos).collect();
-                let p2: Vec<_> = p.iter().map(|x| world_to_chunk(*x)).collect();
-                let keys: Vec<_> = self.orders.keys().cloned().collect();
-                for k in keys {
-                    if !p2
-                        .iter()
-                        .any(|y| (y - k).map(|x| x as f32).norm() <= self.config.draw_chunks as f32)
-                    {
-                        self.orders.remove(&k);
+    /// Finds a player by name
+    fn player(&self, name: &str) -> Result<&Player, String> {
+        self.players
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| format!("There's nobody called {} here", name))
+    }
+
+    /// Runs a command from a player, or from the console if `from` is `None`, which can do anything.
+    /// Returns what to tell whoever ran it.
+    fn command(&mut self, from: Option<usize>, line: &str) -> Result<String, String> {
+        let (permission, sender_name) = match from {
+            Some(id) => {
+                // They might have left since they sent it
+                let p = self
+                    .players
+                    .iter()
+                    .find(|p| p.id == id)
+                    .ok_or("You aren't here")?;
+                (p.permission, Some(p.name.clone()))
+            }
+            None => (Permission::Console, None),
+        };
+
+        let cmd = Command::parse(line)?;
+        if cmd.permission() > permission {
+            return Err(format!("You aren't allowed to use {}", line));
+        }
+        if let Some(name) = &sender_name {
+            println!("{} ran {}", name, line);
+        }
+
+        match cmd {
+            Command::Help => Ok(help(permission)),
+            Command::Tp { who, to } => {
+                let who = who
+                    .or(sender_name)
+                    .ok_or("The console has to say who to teleport")?;
+                let pos = match to {
+                    Target::Pos(pos) => pos,
+                    Target::Player(name) => self.player(&name)?.pos,
+                };
+                // The client moves itself, and then tells us where it is like it normally would
+                self.player(&who)?.conn.send(Message::Teleport(pos));
+                Ok(format!(
+                    "Teleported {} to {:.1} {:.1} {:.1}",
+                    who, pos.x, pos.y, pos.z
+                ))
+            }
+            Command::SetBlock(pos, mat) => {
+                let center = pos.map(|x| x as f32 + 0.5);
+                let loc = world_to_chunk(center);
+                {
+                    let mut world = self.world.write().unwrap();
+                    if !world.contains_chunk(loc) {
+                        return Err("That block isn't loaded".to_string());
                     }
+                    world.set_block(center, mat);
+                }
+                self.dirty.insert(loc);
+                self.send_chunks(&[loc]);
+                self.save_dirty();
+                Ok(format!("Set the block to {:?}", mat))
+            }
+            Command::Fill(a, b, mat) => {
+                let min = a.zip_map(&b, |a, b| a.min(b));
+                let max = a.zip_map(&b, |a, b| a.max(b));
+                let size = (max - min).map(|x| x as i64 + 1);
+                let count = size.x * size.y * size.z;
+                if count > MAX_FILL {
+                    return Err(format!(
+                        "That's {} blocks, but you can only fill {} at once",
+                        count, MAX_FILL
+                    ));
                 }
-                self.ch.0.send(ChunkMessage::Players(p)).unwrap();
-            }
-
-            while let Ok(m) = self.ch.1.try_recv() {
-                match m {
-                    ChunkMessage::LoadChunks(x) => {
-                        let batches = {
-                            let mut batches = HashMap::new();
-                            let world = self.world.read().unwrap();
-                            for i in &x {
-                                if let Some(v) = self.orders.remove(i) {
-                                    if let Some(c) = world.chunk(*i) {
-                                        for (id, conn) in v {
-                                            batches
-                                                .entry(id)
-                                                .or_insert_with(|| (conn, Vec::new()))
-                                                .1
-                                                .push((*i, c.clone()));
-                                        }
-                                    } else {
-                                        println!("WARNING: chunk thread told us it's loaded, but it isn't!");
-                                    }
+                let aabb = Aabb::new(min.map(|x| x as f32), max.map(|x| x as f32 + 1.0));
+                let changed = {
+                    let mut world = self.world.write().unwrap();
+                    // Like /setblock, only fill if all of it is loaded, so the count we report is right
+                    let cmin = world_to_chunk(min.map(|x| x as f32 + 0.5));
+                    let cmax = world_to_chunk(max.map(|x| x as f32 + 0.5));
+                    for x in cmin.x..=cmax.x {
+                        for y in cmin.y..=cmax.y {
+                            for z in cmin.z..=cmax.z {
+                                if !world.contains_chunk(Vector3::new(x, y, z)) {
+                                    return Err("Some of those blocks aren't loaded".to_string());
                                 }
                             }
-                            batches
-                        };
-                        for (_, (conn, v)) in batches {
-                            conn.send(Message::Chunks(v));
                         }
                     }
-                    ChunkMessage::UpdateChunks(v) => {
-                        let mut batches = HashMap::new();
-                        for i in v {
-                            for p in &self.players {
-                                if (world_to_chunk(p.pos) - i).map(|x| x as f32).norm()
-                                    <= self.config.draw_chunks as f32
-                                {
+                    world.fill_aabb(aabb, mat)
+                };
+                self.dirty.extend(changed.iter().cloned());
+                self.send_chunks(&changed);
+                self.save_dirty();
+                Ok(format!("Filled {} blocks with {:?}", count, mat))
+            }
+            Command::Time(TimeCommand::Query) => Ok(format!(
+                "It's {}, and the clock is {}",
+                clock(self.time_of_day()),
+                if self.info.time_frozen {
+                    "frozen"
+                } else {
+                    "running"
+                }
+            )),
+            Command::Time(TimeCommand::Set(t)) => {
+                self.set_time_of_day(t);
+                Ok(format!("Set the time to {}", clock(t)))
+            }
+            Command::Time(TimeCommand::Freeze) => {
+                self.freeze_time(true);
+                Ok("Froze the clock".to_string())
+            }
+            Command::Time(TimeCommand::Unfreeze) => {
+                self.freeze_time(false);
+                Ok("Started the clock".to_string())
+            }
+            Command::Seed => Ok(format!("The seed is {}", self.config.seed)),
+            Command::Save => {
+                self.save();
+                Ok("Saved the world".to_string())
+            }
+            Command::Kick(name, reason) => {
+                let i = self
+                    .players
+                    .iter()
+                    .position(|p| p.name == name)
+                    .ok_or_else(|| format!("There's nobody called {} here", name))?;
+                // They're running the server, so kicking them would stop it
+                if matches!(*self.players[i].conn, Connection::Local(..)) {
+                    return Err(format!(
+                        "{} is hosting the server, so they can't be kicked",
+                        name
+                    ));
+                }
+                let p = self.players.remove(i);
+                p.conn.send(Message::Chat(match &reason {
+                    Some(reason) => format!("You were kicked: {}", reason),
+                    None => "You were kicked".to_string(),
+                }));
+                p.conn.send(Message::Leave);
+                self.remove_player(&p);
+                self.players_changed();
+                Ok(format!("Kicked {}", name))
+            }
+            Command::Say(text) => {
+                self.say(from, &text);
+                Ok(String::new())
+            }
+            Command::Status => Ok(self.status()),
+            Command::Stop => {
+                self.stop.stop();
+                Ok("Stopping the server".to_string())
+            }
+        }
+    }
+
+    /// Handles a message the chunk thread sent us
+    fn chunk_message(&mut self, m: ChunkMessage) {
+        match m {
+            ChunkMessage::LoadChunks(x) => {
+                // Everybody who wanted some of these might have left while they were loading
+                let unused: Vec<_> = x
+                    .iter()
+                    .filter(|i| !self.refs.contains_key(i))
+                    .cloned()
+                    .collect();
+                if !unused.is_empty() {
+                    let mut world = self.world.write().unwrap();
+                    for i in unused {
+                        if let Some(chunk) = world.remove_chunk(i) {
+                            self.ch.0.send(ChunkMessage::UnloadChunk(i, chunk)).unwrap();
+                        }
+                    }
+                }
+
+                let batches = {
+                    let mut batches = HashMap::new();
+                    let world = self.world.read().unwrap();
+                    for i in &x {
+                        if let Some(v) = self.orders.remove(i) {
+                            if let Some(c) = world.chunk(*i) {
+                                for (id, conn) in v {
                                     batches
-                                        .entry(p.id)
-                                        .or_insert((p.conn.clone(), Vec::new()))
+                                        .entry(id)
+                                        .or_insert_with(|| (conn, Vec::new()))
                                         .1
-                                        .push(i);
+                                        .push((*i, c.clone()));
                                 }
+                            } else {
+                                println!(
+                                    "WARNING: chunk thread told us it's loaded, but it isn't!"
+                                );
                             }
                         }
-                        let world = self.world.read().unwrap();
-                        for (_, (conn, v)) in batches {
-                            conn.send(Message::Chunks(
-                                v.into_iter()
-                                    .filter_map(|x| world.chunks.get(&x).cloned().map(|y| (x, y)))
-                                    .collect(),
-                            ))
-                            .unwrap();
-                        }
                     }
-                    _ => panic!("Chunk thread sent {:?}", m),
+                    batches
+                };
+                for (id, (conn, v)) in batches {
+                    let v = match self.players.iter_mut().find(|p| p.id == id) {
+                        Some(p) => p.prepare(&self.config, v),
+                        None => v,
+                    };
+                    conn.send(Message::Chunks(v));
                 }
             }
+            ChunkMessage::UpdateChunks(v) => self.send_chunks(&v),
+            _ => panic!("Chunk thread sent {:?}", m),
         }
-        self.unload_all();
-        for p in self.players {
-            p.conn.send(Message::Leave);
+    }
+
+    /// Checks whether a player with their eyes at `eye` is allowed to set the block at `pos`, and sets it if they are.
+    /// `players` is where everybody's eyes are, so we don't put solid blocks inside anyone.
+    /// Returns the chunk that changed, or None if the edit was rejected.
+    fn edit(
+        &mut self,
+        eye: Vector3<f32>,
+        players: &[Vector3<f32>],
+        pos: Vector3<i32>,
+        block: Block,
+    ) -> Option<Vector3<i32>> {
+        let min = pos.map(|x| x as f32);
+        let aabb = Aabb::new(min, min + Vector3::repeat(1.0));
+        let center = min + Vector3::repeat(0.5);
+
+        // Leave a block of slack, since the client measures to the face it hit and might be a bit behind
+        if (center - eye).norm() > REACH + 1.0 {
+            return None;
+        }
+        // A block the client made up, like Wrong or air with a state
+        if block.mat == Material::Wrong || Block::decode(block.leaf()) != Some(block) {
+            return None;
+        }
+        if block.mat.is_solid() && players.iter().any(|&x| player_aabb(x).intersects(&aabb)) {
+            return None;
+        }
+
+        let loc = world_to_chunk(center);
+        let mut world = self.world.write().unwrap();
+        if !world.contains_chunk(loc) {
+            return None;
+        }
+        world.set_block(center, block);
+        self.dirty.insert(loc);
+        Some(loc)
+    }
+
+    /// Sends copies of the chunks that have been edited to the chunk thread to be saved
+    fn save_dirty(&mut self) {
+        let world = self.world.read().unwrap();
+        for loc in self.dirty.drain() {
+            if let Some(chunk) = world.chunk(loc) {
+                self.ch
+                    .0
+                    .send(ChunkMessage::SaveChunk(loc, chunk.clone()))
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Tells each player where the other players close enough for them to see are
+    fn send_players(&self) {
+        let states: Vec<_> = self
+            .players
+            .iter()
+            .map(|p| PlayerState {
+                id: p.id,
+                pos: p.pos,
+                dir: p.dir,
+            })
+            .collect();
+        for p in &self.players {
+            let range = p.draw_chunks as f32 * CHUNK_SIZE;
+            let near = states
+                .iter()
+                .filter(|s| s.id != p.id && (s.pos - p.pos).norm() <= range)
+                .cloned()
+                .collect();
+            p.conn.send(Message::Players(near));
+        }
+    }
+
+    /// Sends the current version of these chunks to every player close enough to have them loaded
+    fn send_chunks(&mut self, v: &[Vector3<i32>]) {
+        let world = self.world.read().unwrap();
+        for p in &mut self.players {
+            let c = world_to_chunk(p.pos);
+            let draw_chunks = p.draw_chunks as f32;
+            let chunks = v
+                .iter()
+                .filter(|&&i| (c - i).map(|x| x as f32).norm() <= draw_chunks)
+                .filter_map(|&x| world.chunks.get(&x).cloned().map(|y| (x, y)))
+                .collect::<Vec<_>>();
+            if !chunks.is_empty() {
+                let chunks = p.prepare(&self.config, chunks);
+                p.conn.send(Message::Chunks(chunks));
+            }
         }
     }
 
@@ -216,31 +1005,17 @@ impl Server {
     fn load_chunks_around(
         &mut self,
         pos: Vector3<f32>,
+        draw_chunks: usize,
     ) -> (Vec<Vector3<i32>>, Vec<(Vector3<i32>, Chunk)>) {
         let chunk_pos = world_to_chunk(pos);
 
-        let mut to_load = Vec::new();
-
-        let draw_chunks = self.config.draw_chunks as i32;
-
-        for x in -draw_chunks..draw_chunks {
-            for y in -draw_chunks..draw_chunks {
-                for z in -draw_chunks..draw_chunks {
-                    let p = Vector3::new(x, y, z);
-                    if p.map(|x| x as f32).norm() <= self.config.draw_chunks as f32 {
-                        to_load.push(p);
-                    }
-                }
-            }
-        }
-
-        to_load.sort_by_cached_key(|a| ((a.map(|x| x as f32)).norm() * 10.0) as i32);
+        let mut to_load = chunks_around(chunk_pos, draw_chunks);
+        to_load.sort_by_cached_key(|a| ((a - chunk_pos).map(|x| x as f32).norm() * 10.0) as i32);
 
         let mut to_send = Vec::new();
         let mut to_pass = Vec::new();
         let world = self.world.read().unwrap();
         for p in to_load {
-            let p = chunk_pos + p;
             match world.chunk(p) {
                 Some(chunk) => to_pass.push((p, chunk.clone())),
                 None => to_send.push(p),
@@ -254,13 +1029,14 @@ impl Server {
         }
 
         // If it's already being loaded, don't tell the chunk thread to load it again.
-        // The calling function will add this player to `orders` too, so we don't need to bother here
-        to_send.retain(|x| !self.orders.contains_key(&x));
+        // We still return it, so the calling function adds this player to its `orders` too
+        let load = to_send
+            .iter()
+            .filter(|x| !self.orders.contains_key(x))
+            .cloned()
+            .collect();
 
-        self.ch
-            .0
-            .send(ChunkMessage::LoadChunks(to_send.clone()))
-            .unwrap();
+        self.ch.0.send(ChunkMessage::LoadChunks(load)).unwrap();
         (to_send, to_pass)
     }
 
@@ -271,6 +1047,7 @@ impl Server {
         &mut self,
         old: Vector3<f32>,
         new: Vector3<f32>,
+        draw_chunks: usize,
     ) -> (Vec<Vector3<i32>>, Vec<(Vector3<i32>, Chunk)>) {
         let chunk_old = world_to_chunk(old);
         let chunk_new = world_to_chunk(new);
@@ -279,26 +1056,47 @@ impl Server {
             return (Vec::new(), Vec::new());
         }
 
-        let mut around_old = HashSet::new();
-        let mut around_new = HashSet::new();
-        let draw_chunks = self.config.draw_chunks as i32;
+        let around_old: HashSet<_> = chunks_around(chunk_old, draw_chunks).into_iter().collect();
+        let around_new: HashSet<_> = chunks_around(chunk_new, draw_chunks).into_iter().collect();
+        let to_load = &around_new - &around_old;
+        let to_unload = &around_old - &around_new;
 
-        for x in -draw_chunks..draw_chunks {
-            for y in -draw_chunks..draw_chunks {
-                for z in -draw_chunks..draw_chunks {
-                    let p = Vector3::new(x, y, z);
-                    if p.map(|x| x as f32).norm() <= self.config.draw_chunks as f32 {
-                        around_old.insert(chunk_old + p);
-                        around_new.insert(chunk_new + p);
-                    }
+        self.release(to_unload);
+
+        let world = self.world.read().unwrap();
+        let mut to_send = Vec::new();
+        let mut to_pass = Vec::new();
+        for p in to_load {
+            match world.chunk(p) {
+                Some(chunk) => to_pass.push((p, chunk.clone())),
+                None => to_send.push(p),
+            }
+            match self.refs.get_mut(&p) {
+                Some(x) => *x += 1,
+                None => {
+                    self.refs.insert(p, 1);
                 }
             }
         }
-        let to_load = &around_new - &around_old;
-        let to_unload = &around_old - &around_new;
 
+        to_send.sort_by_cached_key(|a| ((a - chunk_new).map(|x| x as f32).norm() * 10.0) as i32);
+
+        // If it's already being loaded, don't tell the chunk thread to load it again.
+        // We still return it, so the calling function adds this player to its `orders` too
+        let load = to_send
+            .iter()
+            .filter(|x| !self.orders.contains_key(x))
+            .cloned()
+            .collect();
+
+        self.ch.0.send(ChunkMessage::LoadChunks(load)).unwrap();
+        (to_send, to_pass)
+    }
+
+    /// Lowers the refcount on these chunks by one, and unloads the ones nobody's using anymore
+    fn release(&mut self, chunks: impl IntoIterator<Item = Vector3<i32>>) {
         let mut world = self.world.write().unwrap();
-        for i in to_unload {
+        for i in chunks {
             if self.refs.contains_key(&i) {
                 let r = {
                     // Lower the refcount on this chunk by one
@@ -321,32 +1119,53 @@ impl Server {
                 panic!("Tried to unload a chunk that isn't loaded [2]: {:?}", i);
             }
         }
+    }
+}
 
-        let mut to_send = Vec::new();
-        let mut to_pass = Vec::new();
-        for p in to_load {
-            match world.chunk(p) {
-                Some(chunk) => to_pass.push((p, chunk.clone())),
-                None => to_send.push(p),
-            }
-            match self.refs.get_mut(&p) {
-                Some(x) => *x += 1,
-                None => {
-                    self.refs.insert(p, 1);
+/// The chunks a player at `chunk` with view distance `draw_chunks` needs
+fn chunks_around(chunk: Vector3<i32>, draw_chunks: usize) -> Vec<Vector3<i32>> {
+    let radius = draw_chunks as f32;
+    let draw_chunks = draw_chunks as i32;
+
+    let mut chunks = Vec::new();
+    for x in -draw_chunks..draw_chunks {
+        for y in -draw_chunks..draw_chunks {
+            for z in -draw_chunks..draw_chunks {
+                let p = Vector3::new(x, y, z);
+                if p.map(|x| x as f32).norm() <= radius {
+                    chunks.push(chunk + p);
                 }
             }
         }
+    }
+    chunks
+}
 
-        // If it's already being loaded, don't tell the chunk thread to load it again.
-        // The calling function will add this player to `orders` too, so we don't need to bother here
-        to_send.retain(|x| !self.orders.contains_key(&x));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        to_send.sort_by_cached_key(|a| ((a - chunk_new).map(|x| x as f32).norm() * 10.0) as i32);
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn set_time_of_day() {
+        let day = 1000;
+        let mut info = WorldInfo::default();
+        info.set_time_of_day(0.25, day);
+        assert_eq!(info.time, 250);
+        assert_eq!(info.time_of_day(day), 0.25);
 
-        self.ch
-            .0
-            .send(ChunkMessage::LoadChunks(to_send.clone()))
-            .unwrap();
-        (to_send, to_pass)
+        // Going back to earlier in the day skips ahead to tomorrow instead
+        info.set_time_of_day(0.1, day);
+        assert_eq!(info.time, 1100);
+        assert_eq!(info.time_of_day(day), 0.1);
+
+        // Setting it to the time it already is doesn't change anything
+        info.set_time_of_day(0.1, day);
+        assert_eq!(info.time, 1100);
+
+        // Times outside 0 to 1 wrap around
+        info.set_time_of_day(-0.5, day);
+        assert_eq!(info.time, 1500);
+        assert_eq!(info.time_of_day(day), 0.5);
     }
 }
@@ -21,6 +21,7 @@ mod beam {
 
 pub use beam::ty::PushConstants as BeamConstants;
 pub use beam::Shader as Beam;
+pub use fs::ty::Entity;
 pub use fs::ty::MatData;
 pub use fs::ty::PushConstants;
 pub use fs::Shader as Fragment;
@@ -0,0 +1,135 @@
+use crate::common::*;
+
+/// How much of an octree node a shape covers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Coverage {
+    Outside,
+    Partial,
+    Inside,
+}
+
+/// A region of space that edits and queries can be restricted to
+pub trait Shape {
+    /// How much of the cube with center `pos` and side length `size` is inside the shape
+    fn coverage(&self, pos: Vector3<f32>, size: f32) -> Coverage;
+
+    /// Whether the point `p` is inside the shape
+    fn contains(&self, p: Vector3<f32>) -> bool;
+
+    /// The smallest box containing the whole shape
+    fn bounds(&self) -> Aabb;
+}
+
+/// An axis-aligned box. `min` is inclusive and `max` is exclusive, so `Aabb::new(p, p + 1)` is one block.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(a: Vector3<f32>, b: Vector3<f32>) -> Self {
+        Aabb {
+            min: a.zip_map(&b, f32::min),
+            max: a.zip_map(&b, f32::max),
+        }
+    }
+
+    /// The box with center `pos` and side length `size`, like an octree node
+    pub fn cube(pos: Vector3<f32>, size: f32) -> Self {
+        Aabb {
+            min: pos.map(|x| x - size * 0.5),
+            max: pos.map(|x| x + size * 0.5),
+        }
+    }
+
+    pub fn translate(&self, offset: Vector3<f32>) -> Self {
+        Aabb {
+            min: self.min + offset,
+            max: self.max + offset,
+        }
+    }
+
+    /// Whether the two boxes overlap. Boxes that only touch don't count.
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3).all(|i| self.min[i] < other.max[i] && other.min[i] < self.max[i])
+    }
+}
+
+impl Shape for Aabb {
+    fn coverage(&self, pos: Vector3<f32>, size: f32) -> Coverage {
+        let cube = Aabb::cube(pos, size);
+        if !self.intersects(&cube) {
+            Coverage::Outside
+        } else if (0..3).all(|i| cube.min[i] >= self.min[i] && cube.max[i] <= self.max[i]) {
+            Coverage::Inside
+        } else {
+            Coverage::Partial
+        }
+    }
+
+    fn contains(&self, p: Vector3<f32>) -> bool {
+        (0..3).all(|i| p[i] >= self.min[i] && p[i] < self.max[i])
+    }
+
+    fn bounds(&self) -> Aabb {
+        *self
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Sphere {
+    pub center: Vector3<f32>,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Vector3<f32>, radius: f32) -> Self {
+        Sphere { center, radius }
+    }
+}
+
+impl Shape for Sphere {
+    fn coverage(&self, pos: Vector3<f32>, size: f32) -> Coverage {
+        let cube = Aabb::cube(pos, size);
+        let closest = self.center.zip_zip_map(&cube.min, &cube.max, na::clamp);
+        let farthest = self.center.zip_zip_map(&cube.min, &cube.max, |c, a, b| {
+            (c - a).abs().max((c - b).abs())
+        });
+        if (closest - self.center).norm() >= self.radius {
+            Coverage::Outside
+        } else if farthest.norm() <= self.radius {
+            Coverage::Inside
+        } else {
+            Coverage::Partial
+        }
+    }
+
+    fn contains(&self, p: Vector3<f32>) -> bool {
+        (p - self.center).norm() < self.radius
+    }
+
+    fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.center.map(|x| x - self.radius),
+            max: self.center.map(|x| x + self.radius),
+        }
+    }
+}
+
+/// A shape moved by `.1`
+pub struct Translated<'a, S: Shape + ?Sized>(pub &'a S, pub Vector3<f32>);
+
+impl<'a, S: Shape + ?Sized> Shape for Translated<'a, S> {
+    fn coverage(&self, pos: Vector3<f32>, size: f32) -> Coverage {
+        self.0.coverage(pos - self.1, size)
+    }
+
+    fn contains(&self, p: Vector3<f32>) -> bool {
+        self.0.contains(p - self.1)
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.0.bounds().translate(self.1)
+    }
+}
@@ -8,10 +8,10 @@ pub struct Gen {
 }
 
 impl Gen {
-    pub fn new() -> Self {
+    pub fn new(seed: u32) -> Self {
         Gen {
             noise: HybridMulti::new()
-                .set_seed(1)
+                .set_seed(seed)
                 .set_octaves(8)
                 .set_persistence(0.5),
         }
@@ -201,6 +201,11 @@ impl Window {
         )
     }
 
+    /// The swapchain image with index `image_num`, from `Frame`
+    pub fn image(&self, image_num: usize) -> Arc<vulkano::image::SwapchainImage<RawWindow>> {
+        Arc::clone(&self.images[image_num])
+    }
+
     pub fn size(&self) -> (f64, f64) {
         self.size.into()
     }
@@ -224,6 +229,7 @@ impl Window {
             Arc::clone(&self.rpass),
             &mut self.dynamic_state,
         );
+        self.images = new_images;
         true
     }
 
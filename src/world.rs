@@ -2,8 +2,9 @@ use crate::common::*;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
-pub struct World {
-    pub chunks: HashMap<Vector3<i32>, Chunk>,
+/// The loaded chunks. The server stores them as `Chunk`s, and clients as `SharedChunk`s so they can share subtrees.
+pub struct World<C = Chunk> {
+    pub chunks: HashMap<Vector3<i32>, C>,
 }
 
 pub type ArcWorld = Arc<RwLock<World>>;
@@ -11,7 +12,7 @@ pub fn arcworld() -> ArcWorld {
     Arc::new(RwLock::new(World::new()))
 }
 
-impl World {
+impl<C: Octree> World<C> {
     pub fn new() -> Self {
         World {
             chunks: HashMap::new(),
@@ -22,17 +23,17 @@ impl World {
         self.chunks.contains_key(&chunk)
     }
 
-    pub fn locs(&self) -> std::collections::hash_map::Keys<'_, Vector3<i32>, Chunk> {
+    pub fn locs(&self) -> std::collections::hash_map::Keys<'_, Vector3<i32>, C> {
         self.chunks.keys()
     }
 
-    pub fn chunk(&self, k: Vector3<i32>) -> Option<&Chunk> {
+    pub fn chunk(&self, k: Vector3<i32>) -> Option<&C> {
         self.chunks.get(&k)
     }
-    pub fn add_chunk(&mut self, k: Vector3<i32>, v: Chunk) {
+    pub fn add_chunk(&mut self, k: Vector3<i32>, v: C) {
         self.chunks.insert(k, v);
     }
-    pub fn remove_chunk(&mut self, k: Vector3<i32>) -> Option<Chunk> {
+    pub fn remove_chunk(&mut self, k: Vector3<i32>) -> Option<C> {
         self.chunks.remove(&k)
     }
 
@@ -42,14 +43,95 @@ impl World {
         let chunk = self.chunks.get(&chunk)?;
         Some(chunk.block(in_chunk))
     }
-    pub fn set_block(&mut self, k: Vector3<f32>, v: Material) {
+    /// Gets the material and state of the block containing `k`
+    pub fn get_block(&self, k: Vector3<f32>) -> Option<Block> {
+        let chunk = world_to_chunk(k);
+        let in_chunk = k - chunk_to_world(chunk);
+        let chunk = self.chunks.get(&chunk)?;
+        Some(chunk.get_block(in_chunk))
+    }
+    pub fn set_block(&mut self, k: Vector3<f32>, v: impl Into<Block>) {
+        self.set_block_level(k, BLOCK_LEVEL, v);
+    }
+    /// Sets a block of a specific size: `level` is `BLOCK_LEVEL` for full-size blocks,
+    /// `BLOCK_LEVEL + 1` for half-size micro-blocks, and so on up to `MAX_BLOCK_LEVEL`.
+    /// The block set is the one containing `k`.
+    pub fn set_block_level(&mut self, k: Vector3<f32>, level: u32, v: impl Into<Block>) {
         let chunk = world_to_chunk(k);
         let in_chunk = k - chunk_to_world(chunk);
         let chunk = self.chunks.get_mut(&chunk).unwrap();
-        chunk.set_block(in_chunk, CHUNK_SIZE.log2().ceil() as u32, v);
+        chunk.make_mut().set_block(in_chunk, level, v);
     }
 
+    /// Replaces every block inside `shape` with `f` of its current material.
+    /// Blocks that change material lose their state.
+    /// Blocks in chunks that aren't loaded are skipped.
+    /// Returns the chunks that changed.
+    pub fn edit(
+        &mut self,
+        shape: &impl Shape,
+        f: impl Fn(Material) -> Material,
+    ) -> Vec<Vector3<i32>> {
+        let bounds = shape.bounds();
+        let lo = world_to_chunk(bounds.min);
+        let hi = world_to_chunk(bounds.max);
+
+        let mut touched = Vec::new();
+        for x in lo.x..=hi.x {
+            for y in lo.y..=hi.y {
+                for z in lo.z..=hi.z {
+                    let loc = Vector3::new(x, y, z);
+                    if let Some(chunk) = self.chunks.get_mut(&loc) {
+                        if chunk
+                            .make_mut()
+                            .edit(&Translated(shape, -chunk_to_world(loc)), &f)
+                        {
+                            touched.push(loc);
+                        }
+                    }
+                }
+            }
+        }
+        touched
+    }
+
+    pub fn fill_aabb(&mut self, aabb: Aabb, v: Material) -> Vec<Vector3<i32>> {
+        self.edit(&aabb, |_| v)
+    }
+
+    pub fn fill_sphere(
+        &mut self,
+        center: Vector3<f32>,
+        radius: f32,
+        v: Material,
+    ) -> Vec<Vector3<i32>> {
+        self.edit(&Sphere::new(center, radius), |_| v)
+    }
+
+    /// Replaces `from` with `to` inside `shape`
+    pub fn replace(
+        &mut self,
+        shape: &impl Shape,
+        from: Material,
+        to: Material,
+    ) -> Vec<Vector3<i32>> {
+        self.edit(shape, |m| if m == from { to } else { m })
+    }
+
+    /// Casts a ray through the loaded chunks, stopping at unloaded chunks or after `max_t`
     pub fn raycast(&self, ro: Vector3<f32>, rd: Vector3<f32>, max_t: f32) -> Option<RayCast> {
+        self.raycast_filter(ro, rd, max_t, |_| true)
+    }
+
+    /// Like `raycast()`, but goes through blocks where `filter` returns false, like water or air.
+    /// The result's positions are in world space.
+    pub fn raycast_filter(
+        &self,
+        ro: Vector3<f32>,
+        rd: Vector3<f32>,
+        max_t: f32,
+        filter: impl Fn(Material) -> bool,
+    ) -> Option<RayCast> {
         // Adapted from _A Fast Voxel Traversal Algorithm for Ray Tracing_ by Amanatides and Woo
         // Basically DDA
         let mut pos = world_to_chunk(ro);
@@ -58,25 +140,23 @@ impl World {
         // t/chunk
         let tdelta = rd.map(|x| CHUNK_SIZE / x).abs();
         let tstep = rd.map(|x| x.signum() as i32);
-        // t
-        let mut tmax = (chunk_to_world(pos) + rd.map(f32::signum) * CHUNK_SIZE * 0.5
-            - ro
-            - Vector3::repeat(CHUNK_SIZE * 0.5))
-        .zip_map(&rd, |p, r| p / r);
+        // t to the first chunk boundary in each direction
+        let mut tmax = (chunk_to_world(pos) + rd.map(f32::signum) * CHUNK_SIZE * 0.5 - ro)
+            .zip_map(&rd, |p, r| p / r);
 
         loop {
             let chunk = self.chunk(pos)?;
-            if chunk[0..8] != [0; 8] {
-                if let Some(x) = chunk.raycast(
-                    ro - chunk_to_world(pos) + Vector3::repeat(CHUNK_SIZE * 0.5),
-                    rd,
-                    64,
-                ) {
+            if (0..8).any(|i| chunk.node(i) != 0) {
+                let center = chunk_to_world(pos);
+                if let Some(x) = chunk.raycast_filter(ro - center, rd, 64, &filter) {
                     if x.t[0] > max_t {
                         return None;
                     }
+                    let offset = center.map(|x| x as i32);
                     return Some(RayCast {
-                        pos: chunk_to_world(pos) + x.pos,
+                        pos: center + x.pos,
+                        voxel: x.voxel + offset,
+                        adjacent: x.adjacent + offset,
                         ..x
                     });
                 }
@@ -103,10 +183,186 @@ impl World {
             }
         }
     }
+
+    /// Returns the boxes of all the solid leaves that intersect `aabb`, in world space.
+    /// Chunks that aren't loaded count as one big solid box, so nothing falls out of the world while they load.
+    fn solid_boxes(&self, aabb: Aabb) -> Vec<Aabb> {
+        let lo = world_to_chunk(aabb.min);
+        let hi = world_to_chunk(aabb.max);
+
+        let mut boxes = Vec::new();
+        for x in lo.x..=hi.x {
+            for y in lo.y..=hi.y {
+                for z in lo.z..=hi.z {
+                    let loc = Vector3::new(x, y, z);
+                    let center = chunk_to_world(loc);
+                    match self.chunks.get(&loc) {
+                        Some(chunk) => boxes.extend(
+                            chunk
+                                .leaves_in(aabb.translate(-center))
+                                .filter(|l| l.mat.is_solid())
+                                .map(|l| Aabb::cube(l.min + Vector3::repeat(l.size * 0.5), l.size))
+                                .map(|b| b.translate(center)),
+                        ),
+                        None => boxes.push(Aabb::cube(center, CHUNK_SIZE)),
+                    }
+                }
+            }
+        }
+        boxes.retain(|b| b.intersects(&aabb));
+        boxes
+    }
+
+    /// Whether `aabb` intersects any solid blocks, or any chunks that aren't loaded
+    pub fn overlaps(&self, aabb: Aabb) -> bool {
+        !self.solid_boxes(aabb).is_empty()
+    }
+
+    /// Moves `aabb` by as much of `vel` as it can without going into solid blocks or unloaded chunks.
+    /// It moves along Y, then X, then Z, so it can slide along walls and floors.
+    /// Returns the motion it actually made, and the normals of the faces it ran into.
+    /// Blocks that `aabb` already intersects are ignored, so it can't get stuck in them.
+    #[allow(clippy::float_cmp)]
+    pub fn sweep_aabb(&self, aabb: Aabb, vel: Vector3<f32>) -> (Vector3<f32>, Vec<Vector3<f32>>) {
+        // Touching a face doesn't count as intersecting, so we're allowed to be this far into a block to start with
+        const EPSILON: f32 = 0.001;
+
+        // Everything we could run into is somewhere between where we start and where we'd end up
+        let end = aabb.translate(vel);
+        let bounds = Aabb::new(
+            aabb.min.zip_map(&end.min, f32::min),
+            aabb.max.zip_map(&end.max, f32::max),
+        );
+        let boxes = self.solid_boxes(bounds);
+
+        let mut aabb = aabb;
+        let mut motion = Vector3::zeros();
+        let mut normals = Vec::new();
+        for &axis in &[1, 0, 2] {
+            let mut d = vel[axis];
+            if d == 0.0 {
+                continue;
+            }
+            for b in &boxes {
+                // It has to be in our way on the other two axes
+                if !(0..3).all(|i| i == axis || (b.min[i] < aabb.max[i] && aabb.min[i] < b.max[i]))
+                {
+                    continue;
+                }
+                if d > 0.0 && b.min[axis] >= aabb.max[axis] - EPSILON {
+                    d = d.min(b.min[axis] - aabb.max[axis]);
+                } else if d < 0.0 && b.max[axis] <= aabb.min[axis] + EPSILON {
+                    d = d.max(b.max[axis] - aabb.min[axis]);
+                }
+            }
+            if d != vel[axis] {
+                let mut normal = Vector3::zeros();
+                normal[axis] = -vel[axis].signum();
+                normals.push(normal);
+            }
+            motion[axis] = d;
+            let mut offset = Vector3::zeros();
+            offset[axis] = d;
+            aabb = aabb.translate(offset);
+        }
+        (motion, normals)
+    }
 }
 
-impl Extend<(Vector3<i32>, Chunk)> for World {
-    fn extend<T: IntoIterator<Item = (Vector3<i32>, Chunk)>>(&mut self, it: T) {
+impl<C> Extend<(Vector3<i32>, C)> for World<C> {
+    fn extend<T: IntoIterator<Item = (Vector3<i32>, C)>>(&mut self, it: T) {
         self.chunks.extend(it);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two chunks side by side along X, from 0 to 32, with a stone floor from y = 0 to 1.
+    /// Everything else around them isn't loaded.
+    fn floor() -> World {
+        let mut world = World::new();
+        for x in 0..2 {
+            world.add_chunk(Vector3::new(x, 0, 0), Chunk::empty());
+        }
+        for x in 0..32 {
+            for z in 0..16 {
+                world.set_block(
+                    Vector3::new(x as f32 + 0.5, 0.5, z as f32 + 0.5),
+                    Material::Stone,
+                );
+            }
+        }
+        world
+    }
+
+    fn player(min: Vector3<f32>) -> Aabb {
+        Aabb::new(min, min + Vector3::new(0.6, 1.8, 0.6))
+    }
+
+    #[test]
+    fn land_on_floor() {
+        let world = floor();
+        let (motion, normals) = world.sweep_aabb(
+            player(Vector3::new(4.0, 3.0, 4.0)),
+            Vector3::new(0.5, -5.0, 0.0),
+        );
+        assert_eq!(motion, Vector3::new(0.5, -2.0, 0.0));
+        assert_eq!(normals, vec![Vector3::y()]);
+        assert!(!world.overlaps(player(Vector3::new(4.0, 1.0, 4.0))));
+        assert!(world.overlaps(player(Vector3::new(4.0, 0.5, 4.0))));
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn slide_along_wall() {
+        let mut world = floor();
+        for y in 1..4 {
+            for z in 0..16 {
+                world.set_block(
+                    Vector3::new(10.5, y as f32 + 0.5, z as f32 + 0.5),
+                    Material::Stone,
+                );
+            }
+        }
+        // Standing on the floor, walking diagonally into the wall
+        let (motion, normals) = world.sweep_aabb(
+            player(Vector3::new(8.0, 1.0, 4.0)),
+            Vector3::new(3.0, 0.0, 1.0),
+        );
+        assert!((motion.x - 1.4).abs() < 0.0001);
+        assert_eq!(motion.z, 1.0);
+        assert_eq!(normals, vec![-Vector3::x()]);
+    }
+
+    #[test]
+    fn cross_chunk_border() {
+        let world = floor();
+        let (motion, normals) = world.sweep_aabb(
+            player(Vector3::new(14.0, 1.0, 4.0)),
+            Vector3::new(4.0, 0.0, 0.0),
+        );
+        assert_eq!(motion, Vector3::new(4.0, 0.0, 0.0));
+        assert!(normals.is_empty());
+        // Falling onto the floor while standing across the border
+        let (motion, _) = world.sweep_aabb(
+            player(Vector3::new(15.7, 1.5, 4.0)),
+            Vector3::new(0.0, -1.0, 0.0),
+        );
+        assert_eq!(motion, Vector3::new(0.0, -0.5, 0.0));
+    }
+
+    #[test]
+    fn unloaded_chunks_are_solid() {
+        let world = floor();
+        // The chunk at z = 16 isn't loaded
+        let (motion, normals) = world.sweep_aabb(
+            player(Vector3::new(4.0, 1.0, 14.0)),
+            Vector3::new(0.0, 0.0, 3.0),
+        );
+        assert!((motion.z - 1.4).abs() < 0.0001);
+        assert_eq!(normals, vec![-Vector3::z()]);
+        assert!(world.overlaps(player(Vector3::new(4.0, 1.0, 20.0))));
+    }
+}